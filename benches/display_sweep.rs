@@ -0,0 +1,291 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Sweep the display generation over width x height x number of digits.
+// eg:
+// cargo bench --bench display_sweep -- --json report.json --csv report.csv
+// cargo bench --bench display_sweep -- --baseline report.json
+//
+// The generations are deterministic so skcd_bytes/nb_gates are exact; only
+// the time and RSS are noisy cf `--tolerance`.
+// NOTE: the generations run one at a time, in this process, so that peak RSS
+// is meaningful; it is Linux only(0 elsewhere).
+
+use api_circuits::layouts::{self, LayoutParams};
+use api_circuits::skcd::SkcdStats;
+use clap::Parser;
+use lib_circuits_wrapper::ffi::GenerationOptions;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Instant;
+use tempfile::Builder;
+
+#[derive(Parser, Debug)]
+#[clap(about = "Benchmark the display circuits generation")]
+struct Args {
+    #[clap(long, value_delimiter = ',', default_value = "120,224,480")]
+    widths: Vec<u32>,
+
+    #[clap(long, value_delimiter = ',', default_value = "96,192")]
+    heights: Vec<u32>,
+
+    #[clap(long, value_delimiter = ',', default_value = "1,2,4,8")]
+    nb_digits: Vec<u32>,
+
+    /// The best(ie min) wall time of N generations is kept
+    #[clap(long, default_value = "3")]
+    repeats: u32,
+
+    /// Write the report as CSV
+    #[clap(long)]
+    csv: Option<PathBuf>,
+
+    /// Write the report as JSON; usable as a `--baseline`
+    #[clap(long)]
+    json: Option<PathBuf>,
+
+    /// Compare to an earlier `--json` report; exit code 1 on regression
+    #[clap(long)]
+    baseline: Option<PathBuf>,
+
+    /// Allowed increase of the time/RSS vs the baseline eg 0.2 = +20%
+    #[clap(long, default_value = "0.2")]
+    tolerance: f64,
+
+    /// passed by `cargo bench`; ignored
+    #[clap(long, hide = true)]
+    bench: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Measurement {
+    width: u32,
+    height: u32,
+    nb_digits: u32,
+    wall_time_ms: f64,
+    peak_rss_kb: u64,
+    skcd_bytes: u64,
+    nb_gates: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Report {
+    /// a baseline is only meaningful for the same toolchain
+    lib_circuits: String,
+    yosys: String,
+    abc: String,
+    measurements: Vec<Measurement>,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let measurements = match sweep(&args) {
+        Ok(measurements) => measurements,
+        Err(err) => {
+            eprintln!("display_sweep: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let versions = lib_circuits_wrapper::ffi::GetToolchainVersions();
+    let report = Report {
+        lib_circuits: versions.lib_circuits,
+        yosys: versions.yosys,
+        abc: versions.abc,
+        measurements,
+    };
+
+    if let Err(err) = write_reports(&args, &report) {
+        eprintln!("display_sweep: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    match &args.baseline {
+        None => ExitCode::SUCCESS,
+        Some(baseline_path) => match compare_to_baseline(baseline_path, &report, args.tolerance) {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => ExitCode::FAILURE,
+            Err(err) => {
+                eprintln!("display_sweep: {err}");
+                ExitCode::FAILURE
+            }
+        },
+    }
+}
+
+fn sweep(args: &Args) -> Result<Vec<Measurement>, String> {
+    let tmp_dir = Builder::new()
+        .prefix("interstellar-display_sweep")
+        .tempdir()
+        .map_err(|err| err.to_string())?;
+    let skcd_file_path = tmp_dir.path().join("output.skcd.pb.bin");
+    let skcd_file_path_str = skcd_file_path
+        .to_str()
+        .ok_or("tmp path is NOT UTF-8")?
+        .to_string();
+
+    let wrapper = lib_circuits_wrapper::ffi::new_circuit_gen_wrapper();
+    let options = GenerationOptions {
+        deterministic: true,
+    };
+
+    let mut measurements = vec![];
+    for &width in &args.widths {
+        for &height in &args.heights {
+            for &nb_digits in &args.nb_digits {
+                let params = LayoutParams {
+                    nb_digits,
+                    ..layouts::preset("2-digits").ok_or("missing preset")?.params
+                };
+                let digits_bboxes = layouts::compute_bboxes(width, height, &params)?;
+
+                let mut wall_time_ms = f64::INFINITY;
+                reset_peak_rss();
+                for _ in 0..args.repeats.max(1) {
+                    let start = Instant::now();
+                    wrapper
+                        .GenerateDisplaySkcdToFile(
+                            width,
+                            height,
+                            &digits_bboxes,
+                            &skcd_file_path_str,
+                            &options,
+                        )
+                        .map_err(|err| format!("{width}x{height}, {nb_digits} digits: {err}"))?;
+                    wall_time_ms = wall_time_ms.min(start.elapsed().as_secs_f64() * 1000.0);
+                }
+
+                let skcd_bytes = std::fs::metadata(&skcd_file_path)
+                    .map_err(|err| err.to_string())?
+                    .len();
+                let stats =
+                    SkcdStats::from_skcd_file(&skcd_file_path).map_err(|err| err.to_string())?;
+                let measurement = Measurement {
+                    width,
+                    height,
+                    nb_digits,
+                    wall_time_ms,
+                    peak_rss_kb: peak_rss_kb().unwrap_or(0),
+                    skcd_bytes,
+                    nb_gates: stats.nb_gates,
+                };
+                println!("{measurement:?}");
+                measurements.push(measurement);
+            }
+        }
+    }
+
+    Ok(measurements)
+}
+
+fn write_reports(args: &Args, report: &Report) -> Result<(), String> {
+    if let Some(csv_path) = &args.csv {
+        let mut csv =
+            "width,height,nb_digits,wall_time_ms,peak_rss_kb,skcd_bytes,nb_gates\n".to_string();
+        for m in &report.measurements {
+            let _ = writeln!(
+                csv,
+                "{},{},{},{:.3},{},{},{}",
+                m.width,
+                m.height,
+                m.nb_digits,
+                m.wall_time_ms,
+                m.peak_rss_kb,
+                m.skcd_bytes,
+                m.nb_gates
+            );
+        }
+        std::fs::write(csv_path, csv).map_err(|err| err.to_string())?;
+    }
+    if let Some(json_path) = &args.json {
+        let json = serde_json::to_string_pretty(report).map_err(|err| err.to_string())?;
+        std::fs::write(json_path, json).map_err(|err| err.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// return: false if any regression
+fn compare_to_baseline(
+    baseline_path: &Path,
+    report: &Report,
+    tolerance: f64,
+) -> Result<bool, String> {
+    let baseline: Report =
+        serde_json::from_slice(&std::fs::read(baseline_path).map_err(|err| err.to_string())?)
+            .map_err(|err| err.to_string())?;
+    if (&baseline.lib_circuits, &baseline.yosys, &baseline.abc)
+        != (&report.lib_circuits, &report.yosys, &report.abc)
+    {
+        println!("WARNING: the baseline was made with a different toolchain");
+    }
+
+    let baseline: HashMap<_, _> = baseline
+        .measurements
+        .iter()
+        .map(|m| ((m.width, m.height, m.nb_digits), m))
+        .collect();
+    let mut is_ok = true;
+    for m in &report.measurements {
+        let Some(base) = baseline.get(&(m.width, m.height, m.nb_digits)) else {
+            continue;
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let regressions = [
+            (
+                "wall_time_ms",
+                m.wall_time_ms > base.wall_time_ms * (1.0 + tolerance),
+            ),
+            (
+                "peak_rss_kb",
+                m.peak_rss_kb as f64 > base.peak_rss_kb as f64 * (1.0 + tolerance),
+            ),
+            // deterministic: NO tolerance
+            ("skcd_bytes", m.skcd_bytes > base.skcd_bytes),
+            ("nb_gates", m.nb_gates > base.nb_gates),
+        ];
+        for (name, _) in regressions
+            .iter()
+            .filter(|(_, is_regression)| *is_regression)
+        {
+            is_ok = false;
+            println!(
+                "REGRESSION {}x{}, {} digits: {name}: {base:?} -> {m:?}",
+                m.width, m.height, m.nb_digits
+            );
+        }
+    }
+
+    Ok(is_ok)
+}
+
+/// cf "clear_refs" in `man 5 proc`: "5" resets `VmHWM`
+fn reset_peak_rss() {
+    let _ = std::fs::write("/proc/self/clear_refs", "5");
+}
+
+fn peak_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
@@ -22,9 +22,11 @@ fn main() {
             &[
                 "deps/protos/api_circuits/api.proto",
                 "deps/protos/api_circuits/circuits_routes.proto",
+                // our own extensions; NOT in deps/protos b/c they are specific to this server
+                "protos/api_circuits/circuits_ext.proto",
             ],
             // includes
-            &["deps/protos"],
+            &["deps/protos", "protos"],
         )
         .unwrap_or_else(|e| panic!("Failed to compile protos {e:?}"));
 }
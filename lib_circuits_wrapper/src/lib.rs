@@ -34,8 +34,23 @@ pub mod ffi {
         upper_right_corner_y: f32,
     }
 
-    struct SkcdAndMetadata {
-        skcd_buffer: Vec<u8>,
+    /// Passed to each generation
+    #[derive(Debug, Clone, Copy)]
+    struct GenerationOptions {
+        /// identical inputs => byte-identical skcd
+        /// Resets what the wrapper can reach ie the C PRNG and yosys' `autoidx`
+        /// cf `ResetGenerationState`; there is no seed b/c lib_circuits' own RNG
+        /// can NOT be seeded: only its default state is reproducible.
+        /// NOTE: assumes ONE generation per process cf src/generator.rs
+        /// The reproducibility is checked by the `*_deterministic_same_cid` tests.
+        deterministic: bool,
+    }
+
+    /// cf `GetToolchainVersions`
+    struct ToolchainVersions {
+        lib_circuits: String,
+        yosys: String,
+        abc: String,
     }
 
     unsafe extern "C++" {
@@ -45,29 +60,38 @@ pub mod ffi {
 
         fn new_circuit_gen_wrapper() -> UniquePtr<GenerateDisplaySkcdWrapper>;
 
+        /// The versions(ie `git describe`) of lib_circuits and its deps, as
+        /// seen at build time; "unknown" if not available.
+        /// Used for the provenance manifests.
+        fn GetToolchainVersions() -> ToolchainVersions;
+
+        /// The skcd.pb.bin is written to `output_path` instead of being copied
+        /// into a `Vec<u8>`. That avoids the copies
+        /// C++ std::string -> rust::Vec -> IPFS body; the caller then streams the file.
+        ///
         /// * `digits_bboxes` - a list of BBox, one per digit
         /// passed as
         /// (lower_left_corner.x, lower_left_corner.y,
         /// upper_right_corner.x, upper_right_corner.y)
         ///
-        /// DO NOT return a cxx:String b/c those MUST contain valid UTF8/16
-        /// and the returned buffer DO NOT (they are protobuf bin)
-        /// Same with return: &str, String
-        /// terminate called after throwing an instance of 'std::invalid_argument'
-        ///   what():  data for rust::Str is not utf-8
-        ///
-        /// return:
-        /// * `Vec<u8>` ie a serialized skcd.pb.bin
-        /// * config: corresponding to the skcd.pb.bin's config field
-        fn GenerateDisplaySkcd(
+        /// NOTE: NOT cancellable: lib_circuits has no cancellation point inside
+        /// a generation; the server runs each one in a subprocess instead, and
+        /// kills it cf src/generator.rs
+        fn GenerateDisplaySkcdToFile(
             &self,
             width: u32,
             height: u32,
-            // digit_type: DisplayDigitType,
-            // digits_bboxes: &Vec<BBox>,
             digits_bboxes: &Vec<f32>,
-        ) -> SkcdAndMetadata;
-        fn GenerateGenericSkcd(&self, verilog_input_path: &str) -> Vec<u8>;
+            output_path: &str,
+            options: &GenerationOptions,
+        ) -> Result<()>;
+        /// cf `GenerateDisplaySkcdToFile`
+        fn GenerateGenericSkcdToFile(
+            &self,
+            verilog_input_path: &str,
+            output_path: &str,
+            options: &GenerationOptions,
+        ) -> Result<()>;
     }
 }
 
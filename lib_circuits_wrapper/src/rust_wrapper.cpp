@@ -14,58 +14,98 @@
 
 #include "rust_wrapper.h"
 
+#include <cstdlib>
+#include <fstream>
 #include <functional>
+#include <stdexcept>
 
 #include "circuit_lib.h"
+#include "kernel/yosys.h"
 
 // generated
 // needed only if shared structs
 #include "lib-circuits-wrapper/src/lib.rs.h"
 
-GenerateDisplaySkcdWrapper::GenerateDisplaySkcdWrapper() {}
-
-SkcdAndMetadata GenerateDisplaySkcdWrapper::GenerateDisplaySkcd(uint32_t width, uint32_t height,
-                                                                // DisplayDigitType digit_type,
-                                                                // const rust::Vec<BBox> &digits_bboxes
-                                                                const rust::Vec<float> &digits_bboxes) const
+namespace
 {
-  // CHECK: digits_bboxes SHOULD be a list ob bboxes, passed as (x1,y1,x2,y2)
-  size_t digits_bboxes_size = digits_bboxes.size();
-  if (!digits_bboxes_size % 4)
+  /**
+   * MUST be called before the generation.
+   * NOTE: only covers the randomness we can reach from here: yosys' autoidx and
+   * the C PRNG. ABC's own PRNGs are seeded with constants by default, and
+   * lib_circuits does not expose any seed.
+   * Process-wide state: the server runs ONE generation per process so there is
+   * no lock cf src/generator.rs
+   */
+  void ResetGenerationState(const GenerationOptions &options)
   {
-    throw std::invalid_argument("GenerateDisplaySkcd: digits_bboxes must be a list of bboxes(ie size == mod 4)");
+    if (options.deterministic)
+    {
+      // suffix of the "$auto$..." names of the new wires/cells; the names
+      // (and so the ordering of the netlist) depend on it
+      Yosys::autoidx = 1;
+      std::srand(0);
+    }
   }
-  std::vector<std::tuple<float, float, float, float>> digits_bboxes_copy;
-  digits_bboxes_copy.reserve(digits_bboxes_size / 4);
-  for (uint32_t i = 0; i < digits_bboxes_size; i += 4)
+
+  std::vector<std::tuple<float, float, float, float>> ToBBoxes(const rust::Vec<float> &digits_bboxes)
   {
-    digits_bboxes_copy.emplace_back(digits_bboxes[i], digits_bboxes[i + 1],
-                                    digits_bboxes[i + 2], digits_bboxes[i + 3]);
+    // CHECK: digits_bboxes SHOULD be a list ob bboxes, passed as (x1,y1,x2,y2)
+    size_t digits_bboxes_size = digits_bboxes.size();
+    if (!digits_bboxes_size % 4)
+    {
+      throw std::invalid_argument("GenerateDisplaySkcd: digits_bboxes must be a list of bboxes(ie size == mod 4)");
+    }
+    std::vector<std::tuple<float, float, float, float>> digits_bboxes_copy;
+    digits_bboxes_copy.reserve(digits_bboxes_size / 4);
+    for (uint32_t i = 0; i < digits_bboxes_size; i += 4)
+    {
+      digits_bboxes_copy.emplace_back(digits_bboxes[i], digits_bboxes[i + 1],
+                                      digits_bboxes[i + 2], digits_bboxes[i + 3]);
+    }
+    return digits_bboxes_copy;
   }
 
+  void WriteToFile(const std::string &buf_str, rust::Str output_path)
+  {
+    std::ofstream output_file(std::string(output_path), std::ios::binary | std::ios::trunc);
+    output_file.write(buf_str.data(), buf_str.size());
+    output_file.close();
+    if (!output_file)
+    {
+      throw std::runtime_error("could not write: " + std::string(output_path));
+    }
+  }
+}
+
+GenerateDisplaySkcdWrapper::GenerateDisplaySkcdWrapper() {}
+
+// NOTE: lib_circuits returns a std::string so the C++ side still holds the
+// whole circuit once; but it is written as-is, without any other copy.
+void GenerateDisplaySkcdWrapper::GenerateDisplaySkcdToFile(uint32_t width, uint32_t height,
+                                                           const rust::Vec<float> &digits_bboxes,
+                                                           rust::Str output_path,
+                                                           const GenerationOptions &options) const
+{
+  ResetGenerationState(options);
+
   auto buf_str = interstellar::circuits::GenerateDisplaySkcd(width, height,
                                                              interstellar::circuits::DisplayDigitType::seven_segments_png,
-                                                             std::move(digits_bboxes_copy));
+                                                             ToBBoxes(digits_bboxes));
 
-  rust::Vec<u_int8_t> vec;
-  std::copy(buf_str.begin(), buf_str.end(), std::back_inserter(vec));
-
-  SkcdAndMetadata skcd_and_metadata;
-  skcd_and_metadata.skcd_buffer = vec;
-  return skcd_and_metadata;
+  WriteToFile(buf_str, output_path);
 }
 
-rust::Vec<u_int8_t> GenerateDisplaySkcdWrapper::GenerateGenericSkcd(rust::Str verilog_input_path) const
+void GenerateDisplaySkcdWrapper::GenerateGenericSkcdToFile(rust::Str verilog_input_path,
+                                                           rust::Str output_path,
+                                                           const GenerationOptions &options) const
 {
+  ResetGenerationState(options);
+
   auto buf_str = interstellar::circuits::GenerateSkcd({
       std::string(verilog_input_path),
   });
-  // std::vector<uint8_t> vec(buf_str.begin(), buf_str.end());
-  // return vec;
-  // return buf_str;
-  rust::Vec<u_int8_t> vec;
-  std::copy(buf_str.begin(), buf_str.end(), std::back_inserter(vec));
-  return vec;
+
+  WriteToFile(buf_str, output_path);
 }
 
 std::unique_ptr<GenerateDisplaySkcdWrapper> new_circuit_gen_wrapper()
@@ -73,6 +113,26 @@ std::unique_ptr<GenerateDisplaySkcdWrapper> new_circuit_gen_wrapper()
   return std::make_unique<GenerateDisplaySkcdWrapper>();
 }
 
+// cf src/CMakeLists.txt
+#ifndef LIB_CIRCUITS_VERSION
+#define LIB_CIRCUITS_VERSION "unknown"
+#endif
+#ifndef YOSYS_VERSION
+#define YOSYS_VERSION "unknown"
+#endif
+#ifndef ABC_VERSION
+#define ABC_VERSION "unknown"
+#endif
+
+ToolchainVersions GetToolchainVersions()
+{
+  ToolchainVersions versions;
+  versions.lib_circuits = rust::String(LIB_CIRCUITS_VERSION);
+  versions.yosys = rust::String(YOSYS_VERSION);
+  versions.abc = rust::String(ABC_VERSION);
+  return versions;
+}
+
 // #include "cxx-demo/include/blobstore.h"
 // #include "cxx-demo/src/main.rs.h"
 // #include <functional>
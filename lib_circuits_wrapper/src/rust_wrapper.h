@@ -21,7 +21,8 @@
 #include "rust/cxx.h"
 
 // rust-cxx shared struct
-struct SkcdAndMetadata;
+struct ToolchainVersions;
+struct GenerationOptions;
 
 /**
  * Wrapper around interstellar::CircuitPipeline::GenerateDisplaySkcd
@@ -34,16 +35,20 @@ public:
   // TODO not const, but will certainly break Rust's no_std CXX side():
   // self: Pin<&mut GenerateDisplaySkcdWrapper>,
   //  ^^^ could not find `std` in the list of imported crates
-  SkcdAndMetadata GenerateDisplaySkcd(uint32_t width, uint32_t height,
-                                      // DisplayDigitType digit_type,
-                                      // const rust::Vec<BBox> &digits_bboxes
-                                      const rust::Vec<float> &digits_bboxes) const;
+  void GenerateDisplaySkcdToFile(uint32_t width, uint32_t height,
+                                 const rust::Vec<float> &digits_bboxes,
+                                 rust::Str output_path,
+                                 const GenerationOptions &options) const;
 
-  rust::Vec<u_int8_t> GenerateGenericSkcd(rust::Str verilog_input_path) const;
+  void GenerateGenericSkcdToFile(rust::Str verilog_input_path,
+                                 rust::Str output_path,
+                                 const GenerationOptions &options) const;
 
 private:
   // TODO dynamic
   bool allow_cache_ = false;
 };
 
-std::unique_ptr<GenerateDisplaySkcdWrapper> new_circuit_gen_wrapper();
\ No newline at end of file
+std::unique_ptr<GenerateDisplaySkcdWrapper> new_circuit_gen_wrapper();
+
+ToolchainVersions GetToolchainVersions();
\ No newline at end of file
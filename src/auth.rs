@@ -0,0 +1,553 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Authentication(API keys, JWT bearer tokens, mTLS client certificates) and
+// per-caller authorization.
+//
+// The tonic `Interceptor` only authenticates: it does NOT know which RPC is
+// called. It inserts a `Caller` in the request extensions, and each handler
+// then calls `authorize` with the `Permission` it requires.
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tonic::service::Interceptor;
+use tonic::{Request, Status};
+
+use crate::metrics;
+use crate::tls::{self, ClientIdentity};
+
+const API_KEY_HEADER: &str = "x-api-key";
+const AUTHORIZATION_HEADER: &str = "authorization";
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// `GenerateSkcdDisplay`
+    Display,
+    /// `GenerateSkcdGenericFromIpfs`
+    Generic,
+    /// `SkcdExtApi::GarbleSkcd`
+    Garble,
+    /// `SkcdExtApi` management RPCs eg `ListCircuits`, `UnpinCircuit`
+    /// NEVER granted to `Caller::anonymous`
+    Admin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallerKind {
+    /// no auth is configured
+    Anonymous,
+    ApiKey,
+    Jwt,
+    /// mTLS, without API key nor JWT
+    ClientCert,
+}
+
+/// The authenticated caller of a RPC
+#[derive(Debug, Clone)]
+pub struct Caller {
+    pub id: String,
+    pub kind: CallerKind,
+    permissions: HashSet<Permission>,
+    /// the mTLS client certificate, if any, whatever the `kind`
+    pub client_identity: Option<ClientIdentity>,
+}
+
+impl Caller {
+    /// used when no auth is configured: everything is allowed, except `Admin`
+    /// b/c unpinning is destructive; configure an API key for it.
+    #[must_use]
+    pub fn anonymous() -> Self {
+        Self {
+            id: "anonymous".to_string(),
+            kind: CallerKind::Anonymous,
+            permissions: [Permission::Display, Permission::Generic, Permission::Garble]
+                .into_iter()
+                .collect(),
+            client_identity: None,
+        }
+    }
+
+    /// NOT `id` b/c the JWT subjects are unbounded; the API keys ids are
+    /// bounded by the config.
+    #[must_use]
+    pub fn metrics_label(&self) -> &str {
+        match self.kind {
+            CallerKind::Anonymous => "anonymous",
+            CallerKind::ApiKey | CallerKind::ClientCert => &self.id,
+            CallerKind::Jwt => "jwt",
+        }
+    }
+
+    #[must_use]
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+}
+
+/// cf `--auth-config-path`, eg
+/// ```json
+/// {
+///   "api_keys": [
+///     { "id": "mobile-backend", "key_sha256": "5e88...", "permissions": ["display"] }
+///   ],
+///   "jwt": {
+///     "jwks_path": "/etc/api_circuits/jwks.json",
+///     "issuer": "https://auth.example.com",
+///     "audience": "api_circuits"
+///   },
+///   "client_certs": [
+///     { "id": "ops", "fingerprint_sha256": "9f86...", "permissions": ["admin"] }
+///   ]
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub jwt: Option<JwtConfig>,
+    /// only with mTLS cf `--tls-client-ca-path`
+    #[serde(default)]
+    pub client_certs: Vec<ClientCertConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ApiKeyConfig {
+    pub id: String,
+    /// hex encoded SHA-256 of the key; we DO NOT store the keys in clear
+    pub key_sha256: String,
+    pub permissions: HashSet<Permission>,
+}
+
+/// Used when the request has neither API key nor JWT
+#[derive(Debug, Deserialize)]
+pub struct ClientCertConfig {
+    pub id: String,
+    /// cf `ClientIdentity.fingerprint_sha256`; the certificate is verified
+    /// against the client CA during the handshake
+    pub fingerprint_sha256: String,
+    pub permissions: HashSet<Permission>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JwtConfig {
+    /// a standard JWKS(ie {"keys": [...]}); the token's "kid" selects the key
+    pub jwks_path: PathBuf,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// NOTE: HS* are NOT allowed by default: the JWKS is meant to hold public keys
+    #[serde(default = "default_jwt_algorithms")]
+    pub algorithms: Vec<Algorithm>,
+}
+
+fn default_jwt_algorithms() -> Vec<Algorithm> {
+    vec![Algorithm::RS256, Algorithm::ES256, Algorithm::EdDSA]
+}
+
+/// The claims we use; "sub" is the caller id
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    permissions: HashSet<Permission>,
+}
+
+struct ApiKey {
+    id: String,
+    permissions: HashSet<Permission>,
+}
+
+struct ClientCert {
+    id: String,
+    permissions: HashSet<Permission>,
+}
+
+struct JwtVerifier {
+    jwks: JwkSet,
+    issuer: Option<String>,
+    audience: Option<String>,
+    algorithms: Vec<Algorithm>,
+}
+
+pub struct Authenticator {
+    /// key: hex(sha256(api key))
+    api_keys: HashMap<String, ApiKey>,
+    jwt: Option<JwtVerifier>,
+    /// key: `ClientIdentity.fingerprint_sha256`
+    client_certs: HashMap<String, ClientCert>,
+}
+
+impl Authenticator {
+    /// # Errors
+    /// if the file can not be read or is not a valid `AuthConfig`
+    pub fn from_config_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: AuthConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+        Self::from_config(config)
+    }
+
+    /// # Errors
+    /// if the JWKS can not be read or parsed
+    pub fn from_config(config: AuthConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let api_keys = config
+            .api_keys
+            .into_iter()
+            .map(|api_key| {
+                (
+                    api_key.key_sha256.to_lowercase(),
+                    ApiKey {
+                        id: api_key.id,
+                        permissions: api_key.permissions,
+                    },
+                )
+            })
+            .collect();
+
+        let jwt = match config.jwt {
+            Some(jwt_config) => Some(JwtVerifier {
+                jwks: serde_json::from_slice(&std::fs::read(&jwt_config.jwks_path)?)?,
+                issuer: jwt_config.issuer,
+                audience: jwt_config.audience,
+                algorithms: jwt_config.algorithms,
+            }),
+            None => None,
+        };
+
+        let client_certs = config
+            .client_certs
+            .into_iter()
+            .map(|client_cert| {
+                (
+                    client_cert.fingerprint_sha256.to_lowercase(),
+                    ClientCert {
+                        id: client_cert.id,
+                        permissions: client_cert.permissions,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            api_keys,
+            jwt,
+            client_certs,
+        })
+    }
+
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<Caller, Status> {
+        let metadata = request.metadata();
+
+        if let Some(api_key) = metadata.get(API_KEY_HEADER) {
+            let api_key = api_key
+                .to_str()
+                .map_err(|_| Status::unauthenticated("invalid api key"))?;
+            let api_key = self
+                .api_keys
+                .get(&hex::encode(Sha256::digest(api_key.as_bytes())))
+                .ok_or_else(|| Status::unauthenticated("invalid api key"))?;
+
+            return Ok(Caller {
+                id: api_key.id.clone(),
+                kind: CallerKind::ApiKey,
+                permissions: api_key.permissions.clone(),
+                client_identity: None,
+            });
+        }
+
+        if let Some(authorization) = metadata.get(AUTHORIZATION_HEADER) {
+            let token = authorization
+                .to_str()
+                .ok()
+                .and_then(|authorization| authorization.strip_prefix(BEARER_PREFIX))
+                .ok_or_else(|| Status::unauthenticated("expected a Bearer token"))?;
+            let jwt = self
+                .jwt
+                .as_ref()
+                .ok_or_else(|| Status::unauthenticated("JWT authentication is not enabled"))?;
+
+            return jwt.verify(token);
+        }
+
+        if let Some(caller) = tls::client_identity(request)
+            .and_then(|client_identity| self.authenticate_client_cert(&client_identity))
+        {
+            return Ok(caller);
+        }
+
+        Err(Status::unauthenticated("missing credentials"))
+    }
+
+    /// None if the certificate is NOT in the config; the request then needs
+    /// another credential
+    fn authenticate_client_cert(&self, client_identity: &ClientIdentity) -> Option<Caller> {
+        let client_cert = self.client_certs.get(&client_identity.fingerprint_sha256)?;
+
+        Some(Caller {
+            id: client_cert.id.clone(),
+            kind: CallerKind::ClientCert,
+            permissions: client_cert.permissions.clone(),
+            client_identity: None,
+        })
+    }
+}
+
+impl JwtVerifier {
+    fn verify(&self, token: &str) -> Result<Caller, Status> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|err| Status::unauthenticated(format!("invalid token: {err}")))?;
+
+        let kid = header
+            .kid
+            .as_deref()
+            .ok_or_else(|| Status::unauthenticated("invalid token: missing kid"))?;
+        let jwk = self
+            .jwks
+            .find(kid)
+            .ok_or_else(|| Status::unauthenticated("invalid token: unknown kid"))?;
+
+        // DO NOT trust the header's "alg" blindly cf "alg confusion" attacks
+        let algorithm_allowed = match jwk.common.algorithm {
+            Some(jwk_algorithm) => jwk_algorithm == header.alg,
+            None => self.algorithms.contains(&header.alg),
+        };
+        if !algorithm_allowed {
+            return Err(Status::unauthenticated(
+                "invalid token: algorithm not allowed",
+            ));
+        }
+
+        let key = DecodingKey::from_jwk(jwk)
+            .map_err(|err| Status::internal(format!("invalid JWKS: {err}")))?;
+
+        let mut validation = Validation::new(header.alg);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+
+        let token_data = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|err| Status::unauthenticated(format!("invalid token: {err}")))?;
+
+        Ok(Caller {
+            id: token_data.claims.sub,
+            kind: CallerKind::Jwt,
+            permissions: token_data.claims.permissions,
+            client_identity: None,
+        })
+    }
+}
+
+/// cf `SkcdApiServer::with_interceptor`
+/// When `authenticator` is None: no authentication, all the requests are
+/// processed as `Caller::anonymous`.
+/// NOTE: it MUST wrap all the services: `authorize` rejects the requests
+/// without a `Caller`.
+#[derive(Clone, Default)]
+pub struct AuthInterceptor {
+    authenticator: Option<Arc<Authenticator>>,
+}
+
+impl AuthInterceptor {
+    #[must_use]
+    pub fn new(authenticator: Option<Arc<Authenticator>>) -> Self {
+        Self { authenticator }
+    }
+}
+
+impl Interceptor for AuthInterceptor {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let mut caller = match &self.authenticator {
+            Some(authenticator) => authenticator.authenticate(&request).inspect_err(|status| {
+                log::warn!(
+                    "auth: rejected request from {:?}: {}",
+                    request.remote_addr(),
+                    status.message()
+                );
+            })?,
+            None => Caller::anonymous(),
+        };
+        caller.client_identity = tls::client_identity(&request);
+        request.extensions_mut().insert(caller);
+
+        Ok(request)
+    }
+}
+
+/// MUST be called at the start of each RPC handler.
+/// `rpc` is only used for logging/metrics.
+///
+/// # Errors
+/// - `unauthenticated` if the service is NOT wrapped by `AuthInterceptor`;
+///   fail-closed instead of processing it as `Caller::anonymous`
+/// - `permission_denied` if the caller does not have `permission`
+pub fn authorize<T>(
+    request: &Request<T>,
+    rpc: &str,
+    permission: Permission,
+) -> Result<Caller, Status> {
+    authorize_all(request, rpc, &[permission])
+}
+
+/// cf `authorize`; the request is counted once.
+/// `permissions` empty: only authenticated; for the handlers whose permission
+/// depends on the resource, checked with `Caller::has_permission`.
+///
+/// # Errors
+/// cf `authorize`; `permission_denied` if the caller lacks any of `permissions`
+pub fn authorize_all<T>(
+    request: &Request<T>,
+    rpc: &str,
+    permissions: &[Permission],
+) -> Result<Caller, Status> {
+    let caller = request
+        .extensions()
+        .get::<Caller>()
+        .cloned()
+        .ok_or_else(|| {
+            log::error!("auth: {rpc}: no Caller, is the service missing the AuthInterceptor?");
+            Status::unauthenticated("missing credentials")
+        })?;
+
+    if !permissions
+        .iter()
+        .all(|permission| caller.has_permission(*permission))
+    {
+        log::warn!("auth: {} is not allowed to call {rpc}", caller.id);
+        metrics::REQUESTS_DENIED_TOTAL
+            .with_label_values(&[rpc, caller.metrics_label()])
+            .inc();
+        return Err(Status::permission_denied(format!(
+            "{} is not allowed to call {rpc}",
+            caller.id
+        )));
+    }
+
+    metrics::REQUESTS_TOTAL
+        .with_label_values(&[rpc, caller.metrics_label()])
+        .inc();
+    Ok(caller)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        Authenticator::from_config(AuthConfig {
+            api_keys: vec![ApiKeyConfig {
+                id: "display-only".to_string(),
+                key_sha256: hex::encode(Sha256::digest(b"secret")),
+                permissions: [Permission::Display].into_iter().collect(),
+            }],
+            jwt: None,
+            client_certs: vec![ClientCertConfig {
+                id: "ops".to_string(),
+                fingerprint_sha256: "AB01".to_string(),
+                permissions: [Permission::Admin].into_iter().collect(),
+            }],
+        })
+        .unwrap()
+    }
+
+    fn request_with_api_key(api_key: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request
+            .metadata_mut()
+            .insert(API_KEY_HEADER, api_key.parse().unwrap());
+        request
+    }
+
+    #[test]
+    fn api_key_permissions() {
+        let mut interceptor = AuthInterceptor::new(Some(Arc::new(authenticator())));
+
+        let request = interceptor.call(request_with_api_key("secret")).unwrap();
+
+        assert!(authorize(&request, "test", Permission::Display).is_ok());
+        assert_eq!(
+            authorize(&request, "test", Permission::Generic)
+                .unwrap_err()
+                .code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn invalid_or_missing_credentials() {
+        let mut interceptor = AuthInterceptor::new(Some(Arc::new(authenticator())));
+
+        assert_eq!(
+            interceptor
+                .call(request_with_api_key("wrong"))
+                .unwrap_err()
+                .code(),
+            tonic::Code::Unauthenticated
+        );
+        assert_eq!(
+            interceptor.call(Request::new(())).unwrap_err().code(),
+            tonic::Code::Unauthenticated
+        );
+    }
+
+    #[test]
+    fn client_cert_permissions() {
+        let authenticator = authenticator();
+        let client_identity = |fingerprint_sha256: &str| ClientIdentity {
+            common_name: Some("ops".to_string()),
+            fingerprint_sha256: fingerprint_sha256.to_string(),
+        };
+
+        let caller = authenticator
+            .authenticate_client_cert(&client_identity("ab01"))
+            .unwrap();
+        assert_eq!(caller.kind, CallerKind::ClientCert);
+        assert!(caller.has_permission(Permission::Admin));
+        assert!(!caller.has_permission(Permission::Display));
+
+        assert!(authenticator
+            .authenticate_client_cert(&client_identity("cd02"))
+            .is_none());
+    }
+
+    #[test]
+    fn disabled_allows_all_but_admin() {
+        let request = AuthInterceptor::default().call(Request::new(())).unwrap();
+
+        assert!(authorize(&request, "test", Permission::Generic).is_ok());
+        assert_eq!(
+            authorize(&request, "test", Permission::Admin)
+                .unwrap_err()
+                .code(),
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn missing_interceptor_fails_closed() {
+        assert_eq!(
+            authorize(&Request::new(()), "test", Permission::Display)
+                .unwrap_err()
+                .code(),
+            tonic::Code::Unauthenticated
+        );
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// ONE lib_circuits generation, then exit. Spawned by the server for each
+// generation cf src/generator.rs; NOT meant to be run by hand.
+// On error: exit code != 0 and the error on the last line of stderr.
+
+use clap::{Parser, Subcommand};
+use lib_circuits_wrapper::ffi::GenerationOptions;
+use std::path::{Path, PathBuf};
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(subcommand)]
+    circuit: Circuit,
+
+    /// where to write the skcd.pb.bin
+    #[clap(long)]
+    output: PathBuf,
+
+    /// cf `GenerationOptions`
+    #[clap(long)]
+    deterministic: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Circuit {
+    /// cf `GenerateDisplaySkcdToFile`
+    Display {
+        #[clap(long)]
+        width: u32,
+        #[clap(long)]
+        height: u32,
+        /// flattened, 4 per digit
+        #[clap(long, value_delimiter = ',', allow_hyphen_values = true)]
+        digits_bboxes: Vec<f32>,
+    },
+    /// cf `GenerateGenericSkcdToFile`
+    Generic {
+        #[clap(long)]
+        verilog: PathBuf,
+    },
+}
+
+fn main() {
+    // NOT `main() -> Result`: that prints the Debug of the error
+    if let Err(err) = run(&Args::parse()) {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let options = GenerationOptions {
+        deterministic: args.deterministic,
+    };
+    let output = path_to_str(&args.output)?;
+
+    let wrapper = lib_circuits_wrapper::ffi::new_circuit_gen_wrapper();
+    match &args.circuit {
+        Circuit::Display {
+            width,
+            height,
+            digits_bboxes,
+        } => wrapper.GenerateDisplaySkcdToFile(*width, *height, digits_bboxes, output, &options)?,
+        Circuit::Generic { verilog } => {
+            wrapper.GenerateGenericSkcdToFile(path_to_str(verilog)?, output, &options)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The bridge takes paths as `&str`
+fn path_to_str(path: &Path) -> Result<&str, String> {
+    path.to_str()
+        .ok_or_else(|| format!("non UTF-8 path: {}", path.display()))
+}
@@ -0,0 +1,298 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Local computation of the CID `ipfs add` would return, without a node.
+// MUST match the kubo defaults:
+// - chunker "size-262144"
+// - balanced layout, max 174 links per node
+//   cf https://github.com/ipfs/go-unixfs/tree/master/importer/balanced
+// - CIDv0: dag-pb leaves, base58btc; the first leaf is a UnixFS "File", the
+//   others are "Raw" cf `fillNodeRec` in the balanced builder
+// - CIDv1: raw leaves("--raw-leaves" is implied by "--cid-version=1"), base32
+//
+// Used to cross-check the CIDs returned by the IPFS node cf `IpfsStorage`.
+
+use sha2::{Digest, Sha256};
+use std::io::{Error, Read};
+
+pub const CHUNK_SIZE: usize = 256 * 1024;
+pub const MAX_LINKS: usize = 174;
+
+const CODEC_RAW: u8 = 0x55;
+const CODEC_DAG_PB: u8 = 0x70;
+const MULTIHASH_SHA2_256: u8 = 0x12;
+/// `UnixFS` "Data.DataType.Raw"
+const UNIXFS_RAW: u64 = 0;
+/// `UnixFS` "Data.DataType.File"
+const UNIXFS_FILE: u64 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CidVersion {
+    /// "Qm..."
+    #[default]
+    V0,
+    /// "bafy..."/"bafk..."
+    V1,
+}
+
+/// A block of the DAG, as seen by its parent
+struct DagNode {
+    cid_bytes: Vec<u8>,
+    /// number of bytes of the file under this node ie `UnixFS` "filesize"
+    filesize: u64,
+    /// serialized size of this node and all its descendants ie dag-pb "Tsize"
+    tsize: u64,
+}
+
+/// Compute the CID of the content of `reader`, as a string.
+/// `reader` is consumed in chunks; only the CIDs of the leaves are kept in memory.
+///
+/// # Errors
+/// if `reader` fails
+pub fn compute_cid<R: Read>(mut reader: R, version: CidVersion) -> Result<String, Error> {
+    let mut nodes = vec![];
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let len = read_chunk(&mut reader, &mut chunk)?;
+        // NOTE: an empty file is a single empty leaf
+        if len == 0 && !nodes.is_empty() {
+            break;
+        }
+        let unixfs_type = if nodes.is_empty() {
+            UNIXFS_FILE
+        } else {
+            UNIXFS_RAW
+        };
+        nodes.push(leaf_node(&chunk[..len], unixfs_type, version));
+        if len < CHUNK_SIZE {
+            break;
+        }
+    }
+
+    // bottom-up: equivalent to the top-down balanced builder
+    while nodes.len() > 1 {
+        nodes = nodes
+            .chunks(MAX_LINKS)
+            .map(|children| internal_node(children, version))
+            .collect();
+    }
+
+    Ok(nodes
+        .pop()
+        .map(|root| cid_to_string(&root.cid_bytes, version))
+        .unwrap_or_default())
+}
+
+/// Fill `chunk` as much as possible; return the number of bytes read
+fn read_chunk<R: Read>(reader: &mut R, chunk: &mut [u8]) -> Result<usize, Error> {
+    let mut len = 0;
+    while len < chunk.len() {
+        match reader.read(&mut chunk[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// `unixfs_type`: only used for `CidVersion::V0`
+fn leaf_node(data: &[u8], unixfs_type: u64, version: CidVersion) -> DagNode {
+    match version {
+        CidVersion::V0 => {
+            let block = unixfs_leaf_block(data, unixfs_type);
+
+            DagNode {
+                cid_bytes: cid_bytes(CODEC_DAG_PB, &block, version),
+                filesize: data.len() as u64,
+                tsize: block.len() as u64,
+            }
+        }
+        CidVersion::V1 => DagNode {
+            cid_bytes: cid_bytes(CODEC_RAW, data, version),
+            filesize: data.len() as u64,
+            tsize: data.len() as u64,
+        },
+    }
+}
+
+fn unixfs_leaf_block(data: &[u8], unixfs_type: u64) -> Vec<u8> {
+    let mut unixfs = vec![];
+    // "Type" is proto2 "required": written even when 0 ie Raw
+    encode_varint_field(&mut unixfs, 1, unixfs_type);
+    // NOTE: go-unixfs omits "Data" for an empty file
+    if !data.is_empty() {
+        encode_bytes_field(&mut unixfs, 2, data);
+    }
+    encode_varint_field(&mut unixfs, 3, data.len() as u64);
+
+    let mut block = vec![];
+    encode_bytes_field(&mut block, 1, &unixfs);
+    block
+}
+
+fn internal_node(children: &[DagNode], version: CidVersion) -> DagNode {
+    let filesize = children.iter().map(|child| child.filesize).sum();
+
+    let mut unixfs = vec![];
+    encode_varint_field(&mut unixfs, 1, UNIXFS_FILE);
+    encode_varint_field(&mut unixfs, 3, filesize);
+    for child in children {
+        // "blocksizes": repeated, NOT packed(proto2)
+        encode_varint_field(&mut unixfs, 4, child.filesize);
+    }
+
+    // dag-pb canonical form: "Links"(2) BEFORE "Data"(1)
+    let mut block = vec![];
+    for child in children {
+        let mut link = vec![];
+        encode_bytes_field(&mut link, 1, &child.cid_bytes);
+        // empty "Name" is still written by go-merkledag
+        encode_bytes_field(&mut link, 2, &[]);
+        encode_varint_field(&mut link, 3, child.tsize);
+        encode_bytes_field(&mut block, 2, &link);
+    }
+    encode_bytes_field(&mut block, 1, &unixfs);
+
+    DagNode {
+        cid_bytes: cid_bytes(CODEC_DAG_PB, &block, version),
+        filesize,
+        tsize: block.len() as u64 + children.iter().map(|child| child.tsize).sum::<u64>(),
+    }
+}
+
+fn cid_bytes(codec: u8, block: &[u8], version: CidVersion) -> Vec<u8> {
+    let mut cid = match version {
+        // CIDv0 is the bare multihash; always dag-pb
+        CidVersion::V0 => vec![],
+        CidVersion::V1 => vec![0x01, codec],
+    };
+    cid.extend_from_slice(&[MULTIHASH_SHA2_256, 32]);
+    cid.extend_from_slice(&Sha256::digest(block));
+    cid
+}
+
+fn cid_to_string(cid_bytes: &[u8], version: CidVersion) -> String {
+    match version {
+        CidVersion::V0 => base58btc(cid_bytes),
+        // multibase prefix "b": base32 lowercase, no padding
+        CidVersion::V1 => format!("b{}", base32_lower(cid_bytes)),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn encode_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn encode_varint_field(buf: &mut Vec<u8>, field: u64, value: u64) {
+    encode_varint(buf, field << 3);
+    encode_varint(buf, value);
+}
+
+fn encode_bytes_field(buf: &mut Vec<u8>, field: u64, value: &[u8]) {
+    encode_varint(buf, (field << 3) | 2);
+    encode_varint(buf, value.len() as u64);
+    buf.extend_from_slice(value);
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn base58btc(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+    // base 58 digits, little endian
+    let mut digits: Vec<u8> = vec![];
+    for &byte in bytes {
+        let mut carry = u32::from(byte);
+        for digit in &mut digits {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let leading_zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+    std::iter::repeat_n(b'1', leading_zeros)
+        .chain(digits.iter().rev().map(|&digit| ALPHABET[digit as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base32_lower(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut result = String::with_capacity(bytes.len() * 8 / 5 + 1);
+    let mut buffer: u32 = 0;
+    let mut nb_bits = 0;
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        nb_bits += 8;
+        while nb_bits >= 5 {
+            nb_bits -= 5;
+            result.push(char::from(ALPHABET[((buffer >> nb_bits) & 0x1f) as usize]));
+        }
+    }
+    if nb_bits > 0 {
+        result.push(char::from(
+            ALPHABET[((buffer << (5 - nb_bits)) & 0x1f) as usize],
+        ));
+    }
+
+    result
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_cid_empty() {
+        assert_eq!(
+            compute_cid(&b""[..], CidVersion::V0).unwrap(),
+            "QmbFMke1KXqnYyBBWxB74N4c5SBnJMVAiMNRcGu6x1AwQH"
+        );
+        assert_eq!(
+            compute_cid(&b""[..], CidVersion::V1).unwrap(),
+            "bafkreihdwdcefgh4dqkjv67uzcmw7ojee6xedzdetojuzjevtenxquvyku"
+        );
+    }
+
+    #[test]
+    fn test_compute_cid_single_chunk() {
+        // echo "hello world" | ipfs add
+        assert_eq!(
+            compute_cid(&b"hello world\n"[..], CidVersion::V0).unwrap(),
+            "QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o"
+        );
+    }
+
+    #[test]
+    fn test_v0_raw_leaf_encoding() {
+        // "Data"(1) { "Type"(1): 0, "Data"(2): "ab", "filesize"(3): 2 }
+        assert_eq!(
+            unixfs_leaf_block(b"ab", UNIXFS_RAW),
+            [0x0a, 0x08, 0x08, 0x00, 0x12, 0x02, b'a', b'b', 0x18, 0x02]
+        );
+    }
+}
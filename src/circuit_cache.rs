@@ -0,0 +1,163 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// In-memory cache of the display circuits cf `SkcdExtApi::GenerateAndGarbleSkcdDisplay`.
+// A display circuit only depends on its request, so it can be garbled many
+// times without being re-generated nor re-downloaded from IPFS.
+// Small and LRU: each entry is a whole parsed circuit.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::cid::CidVersion;
+use crate::skcd::SkcdCircuit;
+
+/// (width, height, `digits_bboxes` as bits, `cid_version`) b/c f32 is NOT Eq
+/// NOTE: the same circuit has a different `skcd_cid` per `CidVersion`
+type DisplayKey = (u32, u32, Vec<u32>, CidVersion);
+
+#[derive(Clone)]
+pub struct CachedCircuit {
+    pub skcd_cid: String,
+    pub circuit: Arc<SkcdCircuit>,
+}
+
+pub struct DisplayCircuitCache {
+    capacity: usize,
+    /// most recently used first
+    entries: Mutex<VecDeque<(DisplayKey, CachedCircuit)>>,
+}
+
+impl DisplayCircuitCache {
+    /// `capacity` 0: disabled
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn get(
+        &self,
+        width: u32,
+        height: u32,
+        digits_bboxes: &[f32],
+        cid_version: CidVersion,
+    ) -> Option<CachedCircuit> {
+        let key = to_key(width, height, digits_bboxes, cid_version);
+        let mut entries = self.entries.lock().ok()?;
+        let index = entries
+            .iter()
+            .position(|(entry_key, _)| *entry_key == key)?;
+        let entry = entries.remove(index)?;
+        let cached = entry.1.clone();
+        entries.push_front(entry);
+
+        Some(cached)
+    }
+
+    pub fn insert(
+        &self,
+        width: u32,
+        height: u32,
+        digits_bboxes: &[f32],
+        cid_version: CidVersion,
+        cached: CachedCircuit,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        let key = to_key(width, height, digits_bboxes, cid_version);
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|(entry_key, _)| *entry_key != key);
+            entries.truncate(self.capacity - 1);
+            entries.push_front((key, cached));
+        }
+    }
+
+    /// eg when the circuit was unpinned
+    pub fn remove(&self, skcd_cid: &str) {
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.retain(|(_, cached)| cached.skcd_cid != skcd_cid);
+        }
+    }
+}
+
+fn to_key(width: u32, height: u32, digits_bboxes: &[f32], cid_version: CidVersion) -> DisplayKey {
+    (
+        width,
+        height,
+        digits_bboxes.iter().map(|value| value.to_bits()).collect(),
+        cid_version,
+    )
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn cached(skcd_cid: &str) -> CachedCircuit {
+        CachedCircuit {
+            skcd_cid: skcd_cid.to_string(),
+            circuit: Arc::new(SkcdCircuit {
+                nb_inputs: 0,
+                gates: vec![],
+                outputs: vec![],
+            }),
+        }
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = DisplayCircuitCache::new(2);
+        cache.insert(1, 1, &[0.5], CidVersion::V0, cached("Qm1"));
+        cache.insert(2, 2, &[0.5], CidVersion::V0, cached("Qm2"));
+        // "Qm1" is now the most recently used
+        assert_eq!(
+            cache.get(1, 1, &[0.5], CidVersion::V0).unwrap().skcd_cid,
+            "Qm1"
+        );
+        cache.insert(3, 3, &[0.5], CidVersion::V0, cached("Qm3"));
+
+        assert!(cache.get(2, 2, &[0.5], CidVersion::V0).is_none());
+        assert_eq!(
+            cache.get(1, 1, &[0.5], CidVersion::V0).unwrap().skcd_cid,
+            "Qm1"
+        );
+        assert_eq!(
+            cache.get(3, 3, &[0.5], CidVersion::V0).unwrap().skcd_cid,
+            "Qm3"
+        );
+        assert!(cache.get(1, 1, &[0.25], CidVersion::V0).is_none());
+    }
+
+    #[test]
+    fn test_cid_version_in_key() {
+        let cache = DisplayCircuitCache::new(2);
+        cache.insert(1, 1, &[0.5], CidVersion::V0, cached("Qm1"));
+
+        assert!(cache.get(1, 1, &[0.5], CidVersion::V1).is_none());
+        cache.insert(1, 1, &[0.5], CidVersion::V1, cached("bafy1"));
+        assert_eq!(
+            cache.get(1, 1, &[0.5], CidVersion::V0).unwrap().skcd_cid,
+            "Qm1"
+        );
+        assert_eq!(
+            cache.get(1, 1, &[0.5], CidVersion::V1).unwrap().skcd_cid,
+            "bafy1"
+        );
+    }
+}
@@ -0,0 +1,744 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// `SkcdExtApi`: the RPCs specific to this server cf protos/api_circuits/circuits_ext.proto
+// Implemented by the same `SkcdApiServerImpl` as `SkcdApi`; in main.rs both
+// services share it via `from_arc`.
+
+use crate::auth;
+use crate::circuit_cache::CachedCircuit;
+use crate::circuits_routes::interstellarpbapicircuits::skcd_ext_api_server::SkcdExtApi;
+pub use crate::circuits_routes::interstellarpbapicircuits::skcd_ext_api_server::SkcdExtApiServer;
+use crate::circuits_routes::interstellarpbapicircuits::verify_manifest_request::Source;
+use crate::circuits_routes::interstellarpbapicircuits::{
+    skcd_display_batch_result, skcd_display_with_options_request, BatchError, CircuitInfo,
+    DiagnosticSeverity, DisplayOutput, DisplayStyle, DownloadSkcdChunk, DownloadSkcdRequest,
+    GarbleSkcdReply, GarbleSkcdRequest, GarbledEncoding, GateStats,
+    GenerateAndGarbleSkcdDisplayReply, GenerateAndGarbleSkcdDisplayRequest, GenerateOptions,
+    Layout, LayoutParams, ListCircuitsReply, ListCircuitsRequest, ListLayoutsReply,
+    ListLayoutsRequest, Palette, PortDirection, PortMapping, SignedManifest,
+    SkcdDisplayBatchResult, SkcdDisplayRequest, SkcdDisplayWithOptionsRequest,
+    SkcdGenericFromIpfsWithOptionsRequest, SkcdWithOptionsReply, UnpinCircuitReply,
+    UnpinCircuitRequest, ValidateVerilogReply, ValidateVerilogRequest, VerifyManifestReply,
+    VerifyManifestRequest, VerilogDiagnostic, VerilogPort,
+};
+use crate::circuits_routes::{to_cid_version, GeneratedSkcd, SkcdApiServerImpl};
+use crate::compression;
+use crate::display_style;
+use crate::garble;
+use crate::layouts;
+use crate::manifest;
+use crate::pinning::CircuitKind;
+use crate::port_map;
+use crate::rate_limit;
+use crate::skcd::{SkcdCircuit, SkcdStats};
+use crate::yosys;
+use futures_core::Stream;
+use futures_util::future::{try_join_all, BoxFuture, Shared};
+use futures_util::{FutureExt, StreamExt};
+use prost::Message;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status, Streaming};
+
+/// a manifest is a few KB; that is only to bound the download
+const MAX_MANIFEST_BYTES: usize = 1024 * 1024;
+
+/// `GenerateAndGarbleSkcdDisplay`: each one is a blocking task
+const MAX_GARBLINGS_PER_REQUEST: u32 = 100;
+
+/// `GenerateSkcdDisplayBatch`: max number of requests per batch
+const MAX_BATCH_SIZE: u32 = 256;
+/// `GenerateSkcdDisplayBatch`: replies buffered before the client reads them;
+/// the generations are bounded server-wide cf `batch_generation_permits`
+const BATCH_REPLIES_BUFFER: usize = 4;
+
+type BatchItemResult = Result<SkcdWithOptionsReply, BatchError>;
+/// shared by the identical requests of a batch
+type BatchGeneration = Shared<BoxFuture<'static, BatchItemResult>>;
+
+#[tonic::async_trait]
+impl SkcdExtApi for SkcdApiServerImpl {
+    type DownloadSkcdStream =
+        Pin<Box<dyn Stream<Item = Result<DownloadSkcdChunk, Status>> + Send + 'static>>;
+    type GenerateSkcdDisplayBatchStream = ReceiverStream<Result<SkcdDisplayBatchResult, Status>>;
+
+    async fn list_circuits(
+        &self,
+        request: Request<ListCircuitsRequest>,
+    ) -> Result<Response<ListCircuitsReply>, Status> {
+        auth::authorize(&request, "list_circuits", auth::Permission::Admin)?;
+
+        let circuits = self
+            .pins
+            .list()?
+            .into_iter()
+            .map(|circuit| CircuitInfo {
+                kind: circuit.kind.as_str().to_string(),
+                created_at: circuit.created_at,
+                caller: circuit.caller,
+                remote_pinned: circuit.remote_request_id.is_some(),
+                content_encoding: circuit.content_encoding.as_str().to_string(),
+                manifest_cid: circuit.manifest_cid.unwrap_or_default(),
+                garbled_cids: circuit.garbled_cids,
+                skcd_cid: circuit.skcd_cid,
+            })
+            .collect();
+
+        Ok(Response::new(ListCircuitsReply { circuits }))
+    }
+
+    async fn unpin_circuit(
+        &self,
+        request: Request<UnpinCircuitRequest>,
+    ) -> Result<Response<UnpinCircuitReply>, Status> {
+        let caller = auth::authorize(&request, "unpin_circuit", auth::Permission::Admin)?;
+        log::info!(
+            "unpin_circuit request for {}, caller: {}",
+            request.get_ref().skcd_cid,
+            caller.id
+        );
+
+        self.pins.unpin(&request.get_ref().skcd_cid).await?;
+        self.display_cache.remove(&request.get_ref().skcd_cid);
+
+        Ok(Response::new(UnpinCircuitReply {}))
+    }
+
+    async fn generate_skcd_display_with_options(
+        &self,
+        request: Request<SkcdDisplayWithOptionsRequest>,
+    ) -> Result<Response<SkcdWithOptionsReply>, Status> {
+        let display_request = to_display_request(request.get_ref())?;
+        let display_output = to_display_output(request.get_ref().style.as_ref(), &display_request)?;
+        let generated = self
+            .generate_display(
+                "generate_skcd_display_with_options",
+                &request,
+                &display_request,
+                &request.get_ref().options.clone().unwrap_or_default(),
+            )
+            .await?;
+
+        Ok(Response::new(SkcdWithOptionsReply {
+            display_output: Some(display_output),
+            ..to_skcd_with_options_reply(generated)
+        }))
+    }
+
+    async fn generate_skcd_generic_from_ipfs_with_options(
+        &self,
+        request: Request<SkcdGenericFromIpfsWithOptionsRequest>,
+    ) -> Result<Response<SkcdWithOptionsReply>, Status> {
+        let generic_request = request
+            .get_ref()
+            .request
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("missing request"))?;
+        let generated = self
+            .generate_generic(
+                "generate_skcd_generic_from_ipfs_with_options",
+                &request,
+                generic_request,
+                &request.get_ref().options.clone().unwrap_or_default(),
+            )
+            .await?;
+
+        Ok(Response::new(to_skcd_with_options_reply(generated)))
+    }
+
+    async fn generate_skcd_display_batch(
+        &self,
+        request: Request<Streaming<SkcdDisplayWithOptionsRequest>>,
+    ) -> Result<Response<Self::GenerateSkcdDisplayBatchStream>, Status> {
+        const RPC: &str = "generate_skcd_display_batch";
+        let caller = auth::authorize(&request, RPC, auth::Permission::Display)?;
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+        log::info!(
+            "{RPC} request from {:?}, caller: {}",
+            request.remote_addr(),
+            caller.id
+        );
+
+        let this = Arc::new(self.clone());
+        let mut items = request.into_inner();
+        let (tx, rx) = tokio::sync::mpsc::channel(BATCH_REPLIES_BUFFER);
+
+        tokio::spawn(async move {
+            let caller = Arc::new(caller);
+            let client_key: Arc<str> = client_key.into();
+            // key: the encoded request; value: (index of the first one, its generation)
+            let mut generations: HashMap<Vec<u8>, (u32, BatchGeneration)> = HashMap::new();
+            // dropped if the client goes away ie the generations are killed cf generator.rs
+            let mut tasks = JoinSet::new();
+
+            for index in 0.. {
+                let item = tokio::select! {
+                    () = tx.closed() => return,
+                    item = items.message() => item,
+                };
+                let item = match item {
+                    Ok(Some(item)) => item,
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                };
+                if index == MAX_BATCH_SIZE {
+                    let _ = tx
+                        .send(Err(Status::invalid_argument(format!(
+                            "batch is too large: limit is {MAX_BATCH_SIZE} requests"
+                        ))))
+                        .await;
+                    return;
+                }
+
+                // NOT the encoded item: a layout and its bboxes are the same geometry
+                // and the style does NOT change the circuit, so it is set per item
+                // a style error fails the item like a request error ie via the generation
+                let (display_request, display_output) =
+                    match to_display_request(&item).and_then(|display_request| {
+                        let display_output =
+                            to_display_output(item.style.as_ref(), &display_request)?;
+                        Ok((display_request, display_output))
+                    }) {
+                        Ok((display_request, display_output)) => {
+                            (Ok(display_request), Some(display_output))
+                        }
+                        Err(status) => (Err(status), None),
+                    };
+                let key = match &display_request {
+                    Ok(display_request) => SkcdDisplayWithOptionsRequest {
+                        request: Some(display_request.clone()),
+                        options: item.options.clone(),
+                        layout: None,
+                        style: None,
+                    }
+                    .encode_to_vec(),
+                    Err(_) => item.encode_to_vec(),
+                };
+                let (this, caller, client_key) = (this.clone(), caller.clone(), client_key.clone());
+                let (first_index, generation) = generations
+                    .entry(key)
+                    .or_insert_with(move || {
+                        let generation = async move {
+                            let display_request = display_request?;
+                            let _permit = this
+                                .batch_generation_permits
+                                .acquire()
+                                .await
+                                .map_err(|err| Status::internal(err.to_string()))?;
+                            let generated = this
+                                .generate_display_as(
+                                    RPC,
+                                    &caller,
+                                    &client_key,
+                                    &display_request,
+                                    &item.options.unwrap_or_default(),
+                                )
+                                .await?;
+                            Ok::<_, Status>(to_skcd_with_options_reply(generated))
+                        }
+                        .map(|reply| {
+                            reply.map_err(|status| BatchError {
+                                code: status.code() as i32,
+                                message: status.message().to_string(),
+                            })
+                        });
+                        (index, generation.boxed().shared())
+                    })
+                    .clone();
+
+                let tx = tx.clone();
+                tasks.spawn(async move {
+                    let result = match generation.await {
+                        Ok(reply) => {
+                            skcd_display_batch_result::Result::Reply(SkcdWithOptionsReply {
+                                display_output,
+                                ..reply
+                            })
+                        }
+                        Err(error) => skcd_display_batch_result::Result::Error(error),
+                    };
+                    let _ = tx
+                        .send(Ok(SkcdDisplayBatchResult {
+                            index,
+                            result: Some(result),
+                            duplicate_of: (first_index != index).then_some(first_index),
+                        }))
+                        .await;
+                });
+            }
+
+            tokio::select! {
+                () = tx.closed() => {}
+                () = async { while tasks.join_next().await.is_some() {} } => {}
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn download_skcd(
+        &self,
+        request: Request<DownloadSkcdRequest>,
+    ) -> Result<Response<Self::DownloadSkcdStream>, Status> {
+        // BEFORE the lookup: do NOT tell an unauthorized caller which CIDs exist
+        let caller = auth::authorize_all(&request, "download_skcd", &[])?;
+        let skcd_cid = &request.get_ref().skcd_cid;
+        // only our own circuits: this is NOT a general purpose IPFS gateway
+        // and the permission is the one needed to generate this kind of circuit
+        let circuit = self
+            .pins
+            .get(skcd_cid)
+            .ok()
+            .filter(|circuit| {
+                caller.has_permission(match circuit.kind {
+                    CircuitKind::Display => auth::Permission::Display,
+                    CircuitKind::Generic => auth::Permission::Generic,
+                })
+            })
+            // same error either way, cf above
+            .ok_or_else(|| Status::not_found(format!("{skcd_cid}: not found")))?;
+        log::info!(
+            "download_skcd request for {skcd_cid}, caller: {}",
+            caller.id
+        );
+
+        // transparent decompression: the client gets the skcd.pb.bin
+        let stream = compression::decompress_stream(
+            self.ipfs.cat_stream(skcd_cid),
+            circuit.content_encoding,
+        )
+        .map(|chunk| {
+            chunk.map(|data| DownloadSkcdChunk {
+                data: data.to_vec(),
+            })
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn verify_manifest(
+        &self,
+        request: Request<VerifyManifestRequest>,
+    ) -> Result<Response<VerifyManifestReply>, Status> {
+        // no permission required: anyone holding a manifest MAY check it
+        let signed_manifest = match &request.get_ref().source {
+            Some(Source::Manifest(signed_manifest)) => manifest::SignedManifest {
+                manifest_json: signed_manifest.manifest_json.clone(),
+                signature: signed_manifest.signature.clone(),
+                public_key: signed_manifest.public_key.clone(),
+            },
+            Some(Source::ManifestCid(manifest_cid)) => {
+                let manifest_buf = self.ipfs.cat(manifest_cid, MAX_MANIFEST_BYTES).await?;
+                serde_json::from_slice(&manifest_buf).map_err(|err| {
+                    Status::invalid_argument(format!("{manifest_cid} is NOT a manifest: {err}"))
+                })?
+            }
+            None => return Err(Status::invalid_argument("missing manifest")),
+        };
+
+        let reply = match self.manifest_signer.verify(&signed_manifest) {
+            Ok(_) => VerifyManifestReply {
+                valid: true,
+                error: String::new(),
+                manifest_json: signed_manifest.manifest_json,
+            },
+            Err(error) => VerifyManifestReply {
+                valid: false,
+                error,
+                manifest_json: String::new(),
+            },
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn validate_verilog(
+        &self,
+        request: Request<ValidateVerilogRequest>,
+    ) -> Result<Response<ValidateVerilogReply>, Status> {
+        let validation = self
+            .validate_verilog_from_ipfs(
+                "validate_verilog",
+                &request,
+                &request.get_ref().verilog_cid,
+            )
+            .await?;
+
+        let diagnostics: Vec<_> = validation
+            .diagnostics
+            .into_iter()
+            .map(|diagnostic| VerilogDiagnostic {
+                file: diagnostic.file,
+                line: diagnostic.line,
+                message: diagnostic.message,
+                severity: if diagnostic.severity == yosys::DiagnosticSeverity::Warning {
+                    DiagnosticSeverity::Warning
+                } else {
+                    DiagnosticSeverity::Error
+                }
+                .into(),
+            })
+            .collect();
+        let ports = validation
+            .ports
+            .into_iter()
+            .map(|port| VerilogPort {
+                name: port.name,
+                direction: to_port_direction_pb(port.direction).into(),
+                width: port.width,
+            })
+            .collect();
+
+        Ok(Response::new(ValidateVerilogReply {
+            valid: !validation.top_module.is_empty()
+                && diagnostics
+                    .iter()
+                    .all(|diagnostic| diagnostic.severity() != DiagnosticSeverity::Error),
+            diagnostics,
+            top_module: validation.top_module,
+            ports,
+        }))
+    }
+
+    async fn list_layouts(
+        &self,
+        request: Request<ListLayoutsRequest>,
+    ) -> Result<Response<ListLayoutsReply>, Status> {
+        // no permission required: static data
+        let (width, height) = (request.get_ref().width, request.get_ref().height);
+
+        let layouts = layouts::PRESETS
+            .iter()
+            .map(|preset| {
+                let digits_bboxes = if width > 0 && height > 0 {
+                    // eg a width too small for the preset
+                    layouts::compute_bboxes(width, height, &preset.params)
+                        .map_err(Status::invalid_argument)?
+                } else {
+                    vec![]
+                };
+                Ok(Layout {
+                    name: preset.name.to_string(),
+                    description: preset.description.to_string(),
+                    params: Some(LayoutParams {
+                        nb_digits: preset.params.nb_digits,
+                        nb_columns: preset.params.nb_columns,
+                        margin_x: preset.params.margin_x,
+                        margin_y: preset.params.margin_y,
+                        spacing_x: preset.params.spacing_x,
+                        spacing_y: preset.params.spacing_y,
+                        digit_aspect_ratio: preset.params.digit_aspect_ratio,
+                    }),
+                    digits_bboxes,
+                })
+            })
+            .collect::<Result<_, Status>>()?;
+
+        Ok(Response::new(ListLayoutsReply { layouts }))
+    }
+
+    async fn garble_skcd(
+        &self,
+        request: Request<GarbleSkcdRequest>,
+    ) -> Result<Response<GarbleSkcdReply>, Status> {
+        let caller = auth::authorize(&request, "garble_skcd", auth::Permission::Garble)?;
+        let skcd_cid = &request.get_ref().skcd_cid;
+        log::info!("garble_skcd request for {skcd_cid}, caller: {}", caller.id);
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+        self.check_rate_limit(&client_key)?;
+
+        let seed = match &request.get_ref().seed {
+            Some(seed) => <[u8; 32]>::try_from(seed.as_slice())
+                .map_err(|_| Status::invalid_argument("seed MUST be 32 bytes"))?,
+            None => rand::random(),
+        };
+
+        let circuit = Arc::new(self.load_skcd_circuit(skcd_cid).await?);
+        let (garbled_cid, encoding) = self
+            .garble_and_store(
+                "garble_skcd",
+                &client_key,
+                skcd_cid,
+                circuit,
+                seed,
+                request.get_ref().cid_version(),
+            )
+            .await?;
+
+        Ok(Response::new(GarbleSkcdReply {
+            garbled_cid,
+            encoding: Some(to_garbled_encoding_pb(encoding)),
+        }))
+    }
+
+    async fn generate_and_garble_skcd_display(
+        &self,
+        request: Request<GenerateAndGarbleSkcdDisplayRequest>,
+    ) -> Result<Response<GenerateAndGarbleSkcdDisplayReply>, Status> {
+        const RPC: &str = "generate_and_garble_skcd_display";
+        // once for both: also on a cache hit
+        let caller = auth::authorize_all(
+            &request,
+            RPC,
+            &[auth::Permission::Display, auth::Permission::Garble],
+        )?;
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+
+        let nb_garblings = request.get_ref().nb_garblings;
+        if !(1..=MAX_GARBLINGS_PER_REQUEST).contains(&nb_garblings) {
+            return Err(Status::invalid_argument(format!(
+                "nb_garblings MUST be in [1, {MAX_GARBLINGS_PER_REQUEST}]"
+            )));
+        }
+        let display_request = request
+            .get_ref()
+            .request
+            .as_ref()
+            .ok_or_else(|| Status::invalid_argument("missing request"))?;
+        let cid_version = request.get_ref().cid_version();
+
+        // NOT cached if it was unpinned since eg by the TTL GC
+        let cached = self
+            .display_cache
+            .get(
+                display_request.width,
+                display_request.height,
+                &display_request.digits_bboxes,
+                to_cid_version(cid_version),
+            )
+            .filter(|cached| self.pins.get(&cached.skcd_cid).is_ok());
+        let is_cached = cached.is_some();
+        let cached = match cached {
+            Some(cached) => {
+                // else done by `generate_display_as`
+                self.check_rate_limit(&client_key)?;
+                cached
+            }
+            None => {
+                let options = GenerateOptions {
+                    cid_version: cid_version.into(),
+                    inline_skcd: true,
+                    ..Default::default()
+                };
+                let generated = self
+                    .generate_display_as(RPC, &caller, &client_key, display_request, &options)
+                    .await?;
+                // empty if larger than `max_inline_skcd_bytes`
+                let circuit = if generated.skcd_buffer.is_empty() {
+                    self.load_skcd_circuit(&generated.skcd_cid).await?
+                } else {
+                    tokio::task::spawn_blocking(move || {
+                        SkcdCircuit::from_skcd_buf(&generated.skcd_buffer)
+                    })
+                    .await
+                    .map_err(|err| Status::internal(err.to_string()))?
+                    .map_err(|err| Status::internal(err.to_string()))?
+                };
+                let cached = CachedCircuit {
+                    skcd_cid: generated.skcd_cid,
+                    circuit: Arc::new(circuit),
+                };
+                self.display_cache.insert(
+                    display_request.width,
+                    display_request.height,
+                    &display_request.digits_bboxes,
+                    to_cid_version(cid_version),
+                    cached.clone(),
+                );
+                cached
+            }
+        };
+        log::info!(
+            "{RPC}: {nb_garblings} garblings of {}, cached: {is_cached}, caller: {}",
+            cached.skcd_cid,
+            caller.id
+        );
+
+        // concurrently: each garbling is its own blocking task
+        let garbled = try_join_all((0..nb_garblings).map(|_| {
+            self.garble_and_store(
+                RPC,
+                &client_key,
+                &cached.skcd_cid,
+                cached.circuit.clone(),
+                rand::random(),
+                cid_version,
+            )
+        }))
+        .await?
+        .into_iter()
+        .map(|(garbled_cid, encoding)| GarbleSkcdReply {
+            garbled_cid,
+            encoding: Some(to_garbled_encoding_pb(encoding)),
+        })
+        .collect();
+
+        Ok(Response::new(GenerateAndGarbleSkcdDisplayReply {
+            skcd_cid: cached.skcd_cid,
+            cached: is_cached,
+            garbled,
+        }))
+    }
+}
+
+/// `item.request`, with the bboxes computed from the layout if any
+fn to_display_request(item: &SkcdDisplayWithOptionsRequest) -> Result<SkcdDisplayRequest, Status> {
+    let mut display_request = item
+        .request
+        .clone()
+        .ok_or_else(|| Status::invalid_argument("missing request"))?;
+    let params = match &item.layout {
+        None => return Ok(display_request),
+        Some(skcd_display_with_options_request::Layout::LayoutName(name)) => {
+            layouts::preset(name)
+                .ok_or_else(|| Status::invalid_argument(format!("unknown layout: {name}")))?
+                .params
+        }
+        Some(skcd_display_with_options_request::Layout::LayoutParams(params)) => {
+            layouts::LayoutParams {
+                nb_digits: params.nb_digits,
+                nb_columns: params.nb_columns,
+                margin_x: params.margin_x,
+                margin_y: params.margin_y,
+                spacing_x: params.spacing_x,
+                spacing_y: params.spacing_y,
+                digit_aspect_ratio: params.digit_aspect_ratio,
+            }
+        }
+    };
+    if !display_request.digits_bboxes.is_empty() {
+        return Err(Status::invalid_argument(
+            "digits_bboxes MUST be empty when a layout is given",
+        ));
+    }
+
+    display_request.digits_bboxes =
+        layouts::compute_bboxes(display_request.width, display_request.height, &params)
+            .map_err(Status::invalid_argument)?;
+    Ok(display_request)
+}
+
+fn to_skcd_with_options_reply(generated: GeneratedSkcd) -> SkcdWithOptionsReply {
+    SkcdWithOptionsReply {
+        skcd_cid: generated.skcd_cid,
+        skcd_buffer: generated.skcd_buffer,
+        content_encoding: generated.content_encoding.as_str().to_string(),
+        manifest: Some(to_signed_manifest_pb(generated.signed_manifest)),
+        manifest_cid: generated.manifest_cid,
+        display_output: None,
+        gate_stats: Some(to_gate_stats_pb(&generated.stats)),
+        port_map: generated
+            .port_map
+            .into_iter()
+            .map(|port| PortMapping {
+                name: port.name,
+                direction: match port.direction {
+                    port_map::PortDirection::Input => PortDirection::Input,
+                    port_map::PortDirection::Output => PortDirection::Output,
+                }
+                .into(),
+                width: port.width,
+                skcd_bits: port.skcd_bits,
+            })
+            .collect(),
+    }
+}
+
+fn to_gate_stats_pb(stats: &SkcdStats) -> GateStats {
+    let estimate = garble::GarbledSizeEstimate::new(stats);
+    let stats = manifest::ManifestStats::from(stats);
+    GateStats {
+        nb_inputs: stats.nb_inputs,
+        nb_outputs: stats.nb_outputs,
+        nb_gates: stats.nb_gates,
+        gates_by_type: stats.gates_by_type.into_iter().collect(),
+        nb_xor_gates: estimate.nb_xor_gates,
+        nb_free_gates: estimate.nb_free_gates,
+        nb_non_free_gates: estimate.nb_non_free_gates,
+        free_xor_size_bytes: estimate.free_xor_bytes,
+        half_gates_size_bytes: estimate.half_gates_bytes,
+    }
+}
+
+fn to_port_direction_pb(direction: yosys::PortDirection) -> PortDirection {
+    match direction {
+        yosys::PortDirection::Input => PortDirection::Input,
+        yosys::PortDirection::Output => PortDirection::Output,
+        yosys::PortDirection::Inout => PortDirection::Inout,
+    }
+}
+
+/// `display_request`: after `to_display_request` ie with the bboxes of the layout
+fn to_display_output(
+    style: Option<&DisplayStyle>,
+    display_request: &SkcdDisplayRequest,
+) -> Result<DisplayOutput, Status> {
+    let default = display_style::DisplayStyle::default();
+    let display_style = style.map_or(default, |style| display_style::DisplayStyle {
+        foreground_rgba: style.foreground_rgba.unwrap_or(default.foreground_rgba),
+        background_rgba: style.background_rgba.unwrap_or(default.background_rgba),
+        grayscale: style.grayscale,
+    });
+    let digits_colors: Vec<_> = style
+        .map(|style| style.digits_colors.as_slice())
+        .unwrap_or_default()
+        .iter()
+        .map(|colors| display_style::DigitColors {
+            foreground_rgba: colors.foreground_rgba,
+            background_rgba: colors.background_rgba,
+        })
+        .collect();
+    let digits_palettes = display_style::digits_palettes(
+        &display_style,
+        &digits_colors,
+        display_request.digits_bboxes.len() / 4,
+    )
+    .map_err(Status::invalid_argument)?;
+
+    Ok(DisplayOutput {
+        bits_per_pixel: display_style::BITS_PER_PIXEL,
+        palette: display_style::palette(&display_style),
+        digits_palettes: digits_palettes
+            .into_iter()
+            .map(|colors| Palette { colors })
+            .collect(),
+        digits_bboxes: display_request.digits_bboxes.clone(),
+    })
+}
+
+fn to_garbled_encoding_pb(encoding: garble::GarbledEncoding) -> GarbledEncoding {
+    GarbledEncoding {
+        delta: encoding.delta.to_vec(),
+        zero_labels: encoding
+            .zero_labels
+            .iter()
+            .map(|zero_label| zero_label.to_vec())
+            .collect(),
+    }
+}
+
+fn to_signed_manifest_pb(signed_manifest: manifest::SignedManifest) -> SignedManifest {
+    SignedManifest {
+        manifest_json: signed_manifest.manifest_json,
+        signature: signed_manifest.signature,
+        public_key: signed_manifest.public_key,
+    }
+}
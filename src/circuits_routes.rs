@@ -12,19 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::auth;
+use crate::cid;
+use crate::circuit_cache::DisplayCircuitCache;
+use crate::compression::{self, ContentEncoding};
+use crate::garble::{self, GarbledEncoding};
+use crate::generator;
+use crate::ipfs::IpfsStorage;
+use crate::manifest::{
+    Manifest, ManifestGeneration, ManifestInputs, ManifestSigner, SignedManifest,
+};
+use crate::metrics;
+use crate::pinning::PinManager;
+use crate::port_map::{self, PortMapping};
+use crate::rate_limit::{self, RateLimiter};
+use crate::skcd::{SkcdCircuit, SkcdStats};
+use crate::yosys::{self, DiagnosticSeverity, VerilogValidation};
 use futures_util::TryStreamExt;
 use interstellarpbapicircuits::skcd_api_server::SkcdApi;
 pub use interstellarpbapicircuits::skcd_api_server::SkcdApiServer;
 use interstellarpbapicircuits::{
-    SkcdDisplayReply, SkcdDisplayRequest, SkcdGenericFromIpfsReply, SkcdGenericFromIpfsRequest,
-};
-use ipfs_api_backend_hyper::{
-    BackendWithGlobalOptions, GlobalOptions, IpfsApi, IpfsClient, TryFromUri,
+    GarbledCircuit, GenerateOptions, SkcdDisplayReply, SkcdDisplayRequest,
+    SkcdGenericFromIpfsReply, SkcdGenericFromIpfsRequest,
 };
-use std::io::Cursor;
-use std::io::Write;
-use std::time::Duration;
+use lib_circuits_wrapper::ffi::GenerationOptions;
+use prost::Message;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tempfile::Builder;
+use tokio::sync::Semaphore;
 use tonic::{Request, Response, Status};
 
 // https://github.com/neoeinstein/protoc-gen-prost/issues/26
@@ -39,82 +57,420 @@ pub mod interstellarpbapicircuits {
     tonic::include_proto!("interstellarpbapicircuits");
 }
 
-// #[derive(Default)]
-pub struct SkcdApiServerImpl {
-    pub ipfs_server_multiaddr: String,
-}
+/// `ValidateVerilog` is parse/elaborate only ie way faster than a synthesis
+const VALIDATE_VERILOG_TIMEOUT: Duration = Duration::from_secs(5);
 
-trait HasIpfsClient {
-    fn ipfs_client(&self) -> Result<BackendWithGlobalOptions<IpfsClient>, Status>;
+/// Limits applied to `generate_skcd_generic_from_ipfs`; the input is arbitrary
+/// Verilog so without those a single request can exhaust the memory or a CPU.
+#[derive(Debug, Clone)]
+pub struct GenericCircuitLimits {
+    /// enforced while downloading from IPFS, ie we never buffer more than that
+    pub max_verilog_bytes: usize,
+    /// max wall time for yosys/abc
+    pub max_synthesis_duration: Duration,
+    /// max number of gates in the resulting skcd
+    pub max_gate_count: u64,
 }
 
-impl HasIpfsClient for SkcdApiServerImpl {
-    fn ipfs_client(&self) -> Result<BackendWithGlobalOptions<IpfsClient>, Status> {
-        log::info!(
-            "ipfs_client: starting with: {}",
-            &self.ipfs_server_multiaddr
-        );
-        Ok(BackendWithGlobalOptions::new(
-            ipfs_api_backend_hyper::IpfsClient::from_multiaddr_str(&self.ipfs_server_multiaddr)
-                .map_err(|err| Status::invalid_argument(err.to_string()))?,
-            GlobalOptions::builder()
-                .timeout(Duration::from_millis(5000))
-                .build(),
-        ))
+impl Default for GenericCircuitLimits {
+    fn default() -> Self {
+        Self {
+            max_verilog_bytes: 1024 * 1024,
+            max_synthesis_duration: Duration::from_secs(300),
+            max_gate_count: 10_000_000,
+        }
     }
 }
 
-#[tonic::async_trait]
-impl SkcdApi for SkcdApiServerImpl {
-    async fn generate_skcd_display(
+/// Clone is cheap: the shared state is behind Arc; eg cloned by the streaming
+/// RPCs, whose tasks outlive the handler
+#[derive(Clone)]
+pub struct SkcdApiServerImpl {
+    /// shared by all the requests cf `IpfsStorage`
+    pub ipfs: Arc<IpfsStorage>,
+    /// every generated skcd is registered here cf `SkcdExtApi`
+    pub pins: Arc<PinManager>,
+    /// None: no rate limiting/quotas
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub generic_limits: GenericCircuitLimits,
+    /// `GenerateOptions.inline_skcd` is ignored above that
+    pub max_inline_skcd_bytes: u64,
+    /// signs the provenance manifest of every generated skcd
+    pub manifest_signer: Arc<ManifestSigner>,
+    /// `SkcdExtApi::GarbleSkcd` loads the whole skcd in memory
+    pub max_garble_skcd_bytes: usize,
+    /// cf `SkcdExtApi::GenerateAndGarbleSkcdDisplay`
+    pub display_cache: Arc<DisplayCircuitCache>,
+    /// max number of garblings running at once, across all the requests;
+    /// each one is a blocking task using a whole core
+    pub garbling_permits: Arc<Semaphore>,
+    /// `SkcdExtApi::GenerateSkcdDisplayBatch`: max number of generations
+    /// running at once, across all the batches
+    pub batch_generation_permits: Arc<Semaphore>,
+    /// force `GenerateOptions.deterministic` for all the requests, including
+    /// those to `SkcdApi` which have no options
+    pub deterministic: bool,
+    /// run as a subprocess on the untrusted Verilog cf yosys.rs
+    pub yosys_path: PathBuf,
+    /// src/bin/generate_skcd.rs: the circuits are generated in a subprocess
+    /// cf generator.rs
+    pub generator_path: PathBuf,
+}
+
+impl SkcdApiServerImpl {
+    pub fn new(ipfs: Arc<IpfsStorage>) -> Self {
+        Self {
+            pins: Arc::new(PinManager::in_memory(ipfs.clone())),
+            ipfs,
+            rate_limiter: None,
+            generic_limits: GenericCircuitLimits::default(),
+            // clients default to a 4 MiB max message size
+            max_inline_skcd_bytes: 3 * 1024 * 1024,
+            manifest_signer: Arc::new(ManifestSigner::ephemeral()),
+            max_garble_skcd_bytes: 64 * 1024 * 1024,
+            display_cache: Arc::new(DisplayCircuitCache::new(16)),
+            garbling_permits: Arc::new(Semaphore::new(4)),
+            batch_generation_permits: Arc::new(Semaphore::new(4)),
+            deterministic: false,
+            yosys_path: PathBuf::from("yosys"),
+            generator_path: PathBuf::from("generate_skcd"),
+        }
+    }
+
+    /// Passed to the bridge
+    fn generation_options(&self, options: &GenerateOptions) -> GenerationOptions {
+        GenerationOptions {
+            deterministic: self.deterministic || options.deterministic,
+        }
+    }
+
+    pub(crate) fn check_rate_limit(&self, client_key: &str) -> Result<(), Status> {
+        match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.check(client_key),
+            None => Ok(()),
+        }
+    }
+
+    /// Compress(if requested), add to IPFS, sign its manifest and register
+    /// the generated skcd
+    async fn store(
         &self,
-        request: Request<SkcdDisplayRequest>,
-    ) -> Result<Response<SkcdDisplayReply>, Status> {
-        log::info!(
-            "generate_skcd_display request from {:?}",
-            request.remote_addr()
-        );
-        let width = request.get_ref().width;
-        let height = request.get_ref().height;
-        let digits_bboxes = request.get_ref().digits_bboxes.clone();
+        skcd_file_path: &Path,
+        inputs: ManifestInputs,
+        skcd_stats: &SkcdStats,
+        caller: &auth::Caller,
+        options: &GenerateOptions,
+        generation: ManifestGeneration,
+    ) -> Result<GeneratedSkcd, Status> {
+        let kind = inputs.kind();
+        let port_map = match &inputs {
+            ManifestInputs::Generic { port_map, .. } => port_map.clone(),
+            ManifestInputs::Display { .. } => vec![],
+        };
+        let cid_version = to_cid_version(options.cid_version());
+        let content_encoding = to_content_encoding(options.compression());
+        let upload_path = match content_encoding {
+            ContentEncoding::Identity => skcd_file_path.to_path_buf(),
+            _ => {
+                let compressed_path = skcd_file_path.with_extension(content_encoding.as_str());
+                compression::compress_file(skcd_file_path, &compressed_path, content_encoding)
+                    .await?;
+                compressed_path
+            }
+        };
 
-        // TODO class member/Trait for "lib_circuits_wrapper::ffi::new_circuit_gen_wrapper()"
-        let lib_circuits_wrapper = tokio::task::spawn_blocking(move || {
-            let wrapper = lib_circuits_wrapper::ffi::new_circuit_gen_wrapper();
+        let skcd_cid = self.ipfs.add_file(&upload_path, cid_version).await?;
 
-            wrapper.GenerateDisplaySkcd(width, height, &digits_bboxes)
+        let signed_manifest = self
+            .manifest_signer
+            .sign(&Manifest::new(
+                inputs,
+                generation,
+                skcd_cid.clone(),
+                content_encoding,
+                skcd_stats,
+            ))
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let manifest_cid = self
+            .ipfs
+            .add(
+                serde_json::to_vec(&signed_manifest)
+                    .map_err(|err| Status::internal(err.to_string()))?,
+                cid_version,
+            )
+            .await?;
+
+        self.pins
+            .register(&skcd_cid, kind, content_encoding, &manifest_cid, &caller.id)
+            .await;
+
+        Ok(GeneratedSkcd {
+            // NOT compressed: the transport is compressed by gRPC
+            skcd_buffer: self.read_inline(skcd_file_path, options).await?,
+            skcd_cid,
+            content_encoding,
+            signed_manifest,
+            manifest_cid,
+            port_map,
+            stats: skcd_stats.clone(),
         })
-        .await
-        .map_err(|err| Status::internal(err.to_string()))?;
+    }
 
-        let data = Cursor::new(lib_circuits_wrapper.skcd_buffer);
+    /// Return the skcd if requested and NOT too large; else the client MUST
+    /// use `DownloadSkcd`
+    async fn read_inline(
+        &self,
+        skcd_file_path: &Path,
+        options: &GenerateOptions,
+    ) -> Result<Vec<u8>, Status> {
+        if !options.inline_skcd {
+            return Ok(vec![]);
+        }
 
-        // TODO error handling, or at least logging
-        let ipfs_result = self
-            .ipfs_client()?
-            .add(data)
-            .await
-            .map_err(|err| Status::unavailable(err.to_string()))?;
+        let len = tokio::fs::metadata(skcd_file_path).await?.len();
+        if len > self.max_inline_skcd_bytes {
+            log::info!(
+                "skcd NOT inlined: {len} bytes, limit is {}",
+                self.max_inline_skcd_bytes
+            );
+            return Ok(vec![]);
+        }
 
-        let reply = SkcdDisplayReply {
-            skcd_cid: ipfs_result.hash,
-        };
+        Ok(tokio::fs::read(skcd_file_path).await?)
+    }
 
-        Ok(Response::new(reply))
+    pub(crate) fn record_cpu_time(&self, client_key: &str, elapsed: Duration) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.record_cpu_time(client_key, elapsed);
+        }
+    }
+}
+
+pub(crate) fn to_cid_version(
+    cid_version: interstellarpbapicircuits::CidVersion,
+) -> cid::CidVersion {
+    match cid_version {
+        interstellarpbapicircuits::CidVersion::V0 => cid::CidVersion::V0,
+        interstellarpbapicircuits::CidVersion::V1 => cid::CidVersion::V1,
     }
+}
 
-    async fn generate_skcd_generic_from_ipfs(
+fn to_content_encoding(compression: interstellarpbapicircuits::Compression) -> ContentEncoding {
+    match compression {
+        interstellarpbapicircuits::Compression::None => ContentEncoding::Identity,
+        interstellarpbapicircuits::Compression::Zstd => ContentEncoding::Zstd,
+        interstellarpbapicircuits::Compression::Gzip => ContentEncoding::Gzip,
+    }
+}
+
+/// cf `port_map::compute_port_map`
+fn to_port_map(
+    validation: &VerilogValidation,
+    skcd_stats: &SkcdStats,
+) -> Result<Vec<PortMapping>, String> {
+    let ports = validation
+        .ports
+        .iter()
+        .map(|port| {
+            let direction = match port.direction {
+                yosys::PortDirection::Input => port_map::PortDirection::Input,
+                yosys::PortDirection::Output => port_map::PortDirection::Output,
+                yosys::PortDirection::Inout => {
+                    return Err(format!("{}: inout ports are NOT supported", port.name))
+                }
+            };
+            Ok((port.name.clone(), direction, port.width))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    port_map::compute_port_map(&ports, skcd_stats.nb_inputs, skcd_stats.nb_outputs)
+}
+
+/// The bridge takes paths as `&str`
+fn path_to_str(path: &Path) -> Result<&str, Status> {
+    path.to_str()
+        .ok_or_else(|| Status::internal(format!("non UTF-8 path: {}", path.display())))
+}
+
+/// Shared between the RPC handler and the blocking garbling, which polls it
+/// cf `garble::garble`, so that an abandoned request stops consuming CPU.
+#[derive(Default)]
+struct CancellationToken {
+    cancelled: AtomicBool,
+}
+
+impl CancellationToken {
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// Cancel the garbling when dropped ie when the handler's future is dropped:
+/// client cancellation, gRPC deadline("grpc-timeout"), or one of our limits.
+/// Also dropped on success but then the garbling is already done.
+struct CancelOnDrop(Arc<CancellationToken>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+/// MUST be called at the end of the blocking garbling.
+/// Records the wasted work if the request was abandoned in the meantime.
+fn record_if_cancelled(rpc: &str, cancellation_token: &CancellationToken, start: Instant) {
+    if cancellation_token.is_cancelled() {
+        record_wasted(rpc, start);
+    }
+}
+
+fn record_wasted(rpc: &str, start: Instant) {
+    log::warn!("{rpc}: generation cancelled after {:?}", start.elapsed());
+    metrics::GENERATIONS_CANCELLED_TOTAL
+        .with_label_values(&[rpc])
+        .inc();
+    metrics::GENERATIONS_WASTED_SECONDS_TOTAL
+        .with_label_values(&[rpc])
+        .inc_by(start.elapsed().as_secs_f64());
+}
+
+/// For the generations in a subprocess cf generator.rs: records the wasted
+/// work if dropped before `finish` ie the request was abandoned(client
+/// cancellation, gRPC deadline, or one of our limits) and the subprocess killed.
+struct AbandonedGeneration {
+    rpc: &'static str,
+    start: Instant,
+    finished: bool,
+}
+
+impl AbandonedGeneration {
+    fn new(rpc: &'static str, start: Instant) -> Self {
+        Self {
+            rpc,
+            start,
+            finished: false,
+        }
+    }
+
+    /// The subprocess exited by itself, successfully or not
+    fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for AbandonedGeneration {
+    fn drop(&mut self) {
+        if !self.finished {
+            record_wasted(self.rpc, self.start);
+        }
+    }
+}
+
+/// Returned by `generate_display`/`generate_generic`
+pub(crate) struct GeneratedSkcd {
+    pub skcd_cid: String,
+    /// empty unless `GenerateOptions.inline_skcd`
+    pub skcd_buffer: Vec<u8>,
+    /// of the blob in IPFS; NOT of `skcd_buffer`
+    pub content_encoding: ContentEncoding,
+    pub signed_manifest: SignedManifest,
+    /// the `signed_manifest` as JSON
+    pub manifest_cid: String,
+    /// empty for the display circuits; also in the manifest
+    pub port_map: Vec<PortMapping>,
+    pub stats: SkcdStats,
+}
+
+impl SkcdApiServerImpl {
+    /// Shared by `SkcdApi::generate_skcd_display` and `SkcdExtApi::generate_skcd_display_with_options`
+    /// `request` is only used for the auth/logs; the params are in `display_request`
+    ///
+    pub(crate) async fn generate_display<T: Sync>(
         &self,
-        request: Request<SkcdGenericFromIpfsRequest>,
-    ) -> Result<Response<SkcdGenericFromIpfsReply>, Status> {
+        rpc: &'static str,
+        request: &Request<T>,
+        display_request: &SkcdDisplayRequest,
+        options: &GenerateOptions,
+    ) -> Result<GeneratedSkcd, Status> {
+        let caller = auth::authorize(request, rpc, auth::Permission::Display)?;
         log::info!(
-            "generate_skcd_generic_from_ipfs request from {:?}",
-            request.remote_addr()
+            "{rpc} request from {:?}, caller: {}, client: {:?}",
+            request.remote_addr(),
+            caller.id,
+            caller.client_identity
         );
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+
+        self.generate_display_as(rpc, &caller, &client_key, display_request, options)
+            .await
+    }
+
+    /// cf `generate_display`; for the callers already authorized eg the batches
+    pub(crate) async fn generate_display_as(
+        &self,
+        rpc: &'static str,
+        caller: &auth::Caller,
+        client_key: &str,
+        display_request: &SkcdDisplayRequest,
+        options: &GenerateOptions,
+    ) -> Result<GeneratedSkcd, Status> {
+        self.check_rate_limit(client_key)?;
+
+        let width = display_request.width;
+        let height = display_request.height;
+
+        // the skcd is written there by C++ then streamed to IPFS cf `IpfsStorage::add_file`
+        let tmp_dir = Builder::new()
+            .prefix("interstellar-circuit_routes-generate_skcd_display")
+            .tempdir()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let skcd_file_path = tmp_dir.path().join("output.skcd.pb.bin");
 
-        let verilog_cid = &request.get_ref().verilog_cid;
+        let generation_options = self.generation_options(options);
 
-        // get the Verilog (.v) from IPFS
+        // in a subprocess, killed if this future is dropped cf generator.rs
+        let generation_start = Instant::now();
+        let abandoned = AbandonedGeneration::new(rpc, generation_start);
+        let generated = generator::generate_display(
+            &self.generator_path,
+            width,
+            height,
+            &display_request.digits_bboxes,
+            &skcd_file_path,
+            &generation_options,
+        )
+        .await;
+        abandoned.finish();
+        self.record_cpu_time(client_key, generation_start.elapsed());
+        generated.map_err(Status::internal)?;
+
+        let skcd_stats = SkcdStats::from_skcd_file(&skcd_file_path)
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let inputs = ManifestInputs::Display {
+            width: display_request.width,
+            height: display_request.height,
+            digits_bboxes: display_request.digits_bboxes.clone(),
+        };
+        self.store(
+            &skcd_file_path,
+            inputs,
+            &skcd_stats,
+            caller,
+            options,
+            generation_options.into(),
+        )
+        .await
+    }
+
+    /// Get the Verilog (.v) from IPFS and write it to `dir`.
+    /// yosys/abc REQUIRE a file b/c they are basically cli
+    /// so either write it on Rust side, or send as std::string to C++ and write it there
+    async fn write_verilog(&self, verilog_cid: &str, dir: &Path) -> Result<PathBuf, Status> {
         // DO NOT use dag_get if the file was "add"
         // The returned bytes would be eg
         // {"Data":{"/":{"bytes":"CAISjgQvL....ZfYWRkGI4E"}},"Links":[]}
@@ -126,60 +482,301 @@ impl SkcdApi for SkcdApiServerImpl {
         //     .await
         //     .unwrap();
         let verilog_buf = self
-            .ipfs_client()?
-            .cat(verilog_cid)
-            .map_ok(|chunk| chunk.to_vec())
-            .try_concat()
+            .ipfs
+            .cat(verilog_cid, self.generic_limits.max_verilog_bytes)
+            .await?;
+
+        let verilog_file_path = dir.join("input.v");
+        // MUST be written(and closed) before returning else we get sporadic
+        // Entered genlib library with 16 gates from file "/home/xxx/Documents/interstellar/api_circuits/lib_circuits_wrapper/deps/lib_circuits/data/verilog/skcd.genlib".
+        // E20230117 13:07:41.909034 26231 verilog_compiler.cpp:59] FilterErrorStreamBuf : Error : ERROR: Can't open input file `/tmp/interstellar-circuit_routes-generate_skcd_generic_from_ipfsQtXDxw/input.v' for reading: No such file or directory
+        // NOTE: NOT a tokio::fs::File: its writes complete in the background,
+        // possibly after it is dropped; `write` awaits them
+        tokio::fs::write(&verilog_file_path, &verilog_buf)
             .await
             .map_err(|err| Status::unavailable(err.to_string()))?;
 
-        // write the buffer to a file in /tmp
-        // yosys/abc REQUIRE file b/c they are basically cli
-        // so either write it on Rust side, or send as std::string to C++ and write it there
+        Ok(verilog_file_path)
+    }
+
+    /// Parse/elaborate only cf `yosys::validate_verilog`.
+    /// The diagnostics refer to `verilog_cid` instead of the tmp file.
+    pub(crate) async fn validate_verilog_from_ipfs<T: Sync>(
+        &self,
+        rpc: &'static str,
+        request: &Request<T>,
+        verilog_cid: &str,
+    ) -> Result<VerilogValidation, Status> {
+        let caller = auth::authorize(request, rpc, auth::Permission::Generic)?;
+        log::info!(
+            "{rpc} request from {:?}, caller: {}, client: {:?}",
+            request.remote_addr(),
+            caller.id,
+            caller.client_identity
+        );
+
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+        self.check_rate_limit(&client_key)?;
+
+        let tmp_dir = Builder::new()
+            .prefix("interstellar-circuit_routes-validate_verilog")
+            .tempdir()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let verilog_file_path = self.write_verilog(verilog_cid, tmp_dir.path()).await?;
+
+        self.run_validate_verilog(&client_key, &verilog_file_path, verilog_cid)
+            .await
+    }
+
+    /// cf `validate_verilog_from_ipfs`; `verilog_file_path` was written by `write_verilog`
+    async fn run_validate_verilog(
+        &self,
+        client_key: &str,
+        verilog_file_path: &Path,
+        verilog_cid: &str,
+    ) -> Result<VerilogValidation, Status> {
+        let verilog_file_path_str = path_to_str(verilog_file_path)?.to_string();
+
+        // NOTE: yosys is killed if this future is dropped cf `yosys::run`
+        let validation_start = Instant::now();
+        let validation = yosys::validate_verilog(
+            &self.yosys_path,
+            verilog_file_path,
+            VALIDATE_VERILOG_TIMEOUT,
+        )
+        .await;
+        self.record_cpu_time(client_key, validation_start.elapsed());
+        let mut validation = validation.map_err(Status::internal)?;
+
+        for diagnostic in &mut validation.diagnostics {
+            if diagnostic.file == verilog_file_path_str {
+                diagnostic.file = verilog_cid.to_string();
+            }
+            diagnostic.message = diagnostic
+                .message
+                .replace(&verilog_file_path_str, verilog_cid);
+        }
+
+        Ok(validation)
+    }
+
+    /// cf `generate_display`
+    pub(crate) async fn generate_generic<T: Sync>(
+        &self,
+        rpc: &'static str,
+        request: &Request<T>,
+        generic_request: &SkcdGenericFromIpfsRequest,
+        options: &GenerateOptions,
+    ) -> Result<GeneratedSkcd, Status> {
+        let caller = auth::authorize(request, rpc, auth::Permission::Generic)?;
+        log::info!(
+            "{rpc} request from {:?}, caller: {}, client: {:?}",
+            request.remote_addr(),
+            caller.id,
+            caller.client_identity
+        );
+
+        let client_key = rate_limit::client_key(&caller, request.remote_addr());
+        self.check_rate_limit(&client_key)?;
+
+        let verilog_cid = &generic_request.verilog_cid;
+
         let tmp_dir = Builder::new()
             .prefix("interstellar-circuit_routes-generate_skcd_generic_from_ipfs")
             .tempdir()
             .map_err(|err| Status::internal(err.to_string()))?;
-        let verilog_file_path = tmp_dir.path().join("input.v");
+        let verilog_file_path = self.write_verilog(verilog_cid, tmp_dir.path()).await?;
+        let skcd_file_path = tmp_dir.path().join("output.skcd.pb.bin");
+
+        // fast: fail early with a proper error, and get the ports for the port map
+        let validation = self
+            .run_validate_verilog(&client_key, &verilog_file_path, verilog_cid)
+            .await?;
+        if let Some(error) = validation
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.severity == DiagnosticSeverity::Error)
         {
-            // MUST drop the file else we get sporadic
-            // Entered genlib library with 16 gates from file "/home/xxx/Documents/interstellar/api_circuits/lib_circuits_wrapper/deps/lib_circuits/data/verilog/skcd.genlib".
-            // E20230117 13:07:41.909034 26231 verilog_compiler.cpp:59] FilterErrorStreamBuf : Error : ERROR: Can't open input file `/tmp/interstellar-circuit_routes-generate_skcd_generic_from_ipfsQtXDxw/input.v' for reading: No such file or directory
-            let mut input_v_file = std::fs::File::create(&verilog_file_path)?;
-            input_v_file
-                .write_all(&verilog_buf)
-                .map_err(|err| Status::unavailable(err.to_string()))?;
+            return Err(Status::invalid_argument(format!(
+                "invalid Verilog: {}:{}: {}",
+                error.file, error.line, error.message
+            )));
         }
 
-        // TODO class member/Trait for "lib_circuits_wrapper::ffi::new_circuit_gen_wrapper()"
-        let lib_circuits_wrapper = tokio::task::spawn_blocking(move || {
-            let wrapper = lib_circuits_wrapper::ffi::new_circuit_gen_wrapper();
+        let generation_options = self.generation_options(options);
 
-            let skcd_pb_buf = wrapper.GenerateGenericSkcd(
-                verilog_file_path
-                    .as_os_str()
-                    .to_str()
-                    .ok_or_else(|| Status::unavailable("as_os_str::to_str FAILED"))?,
-            );
+        // in a subprocess: on timeout, or if this future is dropped, it is
+        // killed ie it does NOT keep running in the background cf generator.rs
+        let max_synthesis_duration = self.generic_limits.max_synthesis_duration;
+        let generation_start = Instant::now();
+        let abandoned = AbandonedGeneration::new(rpc, generation_start);
+        let generated = tokio::time::timeout(
+            max_synthesis_duration,
+            generator::generate_generic(
+                &self.generator_path,
+                &verilog_file_path,
+                &skcd_file_path,
+                &generation_options,
+            ),
+        )
+        .await;
+        self.record_cpu_time(&client_key, generation_start.elapsed());
+        let generated = generated.map_err(|_| {
+            Status::deadline_exceeded(format!(
+                "synthesis took longer than the limit of {max_synthesis_duration:?}"
+            ))
+        })?;
+        abandoned.finish();
+        generated.map_err(Status::internal)?;
 
-            Ok(skcd_pb_buf)
-        })
+        // streamed from the file: generic circuits can be large
+        let skcd_stats = SkcdStats::from_skcd_file(&skcd_file_path)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        if skcd_stats.nb_gates > self.generic_limits.max_gate_count {
+            return Err(Status::invalid_argument(format!(
+                "circuit is too large: {} gates, limit is {}",
+                skcd_stats.nb_gates, self.generic_limits.max_gate_count
+            )));
+        }
+
+        let inputs = ManifestInputs::Generic {
+            verilog_cid: verilog_cid.clone(),
+            port_map: to_port_map(&validation, &skcd_stats).unwrap_or_else(|err| {
+                log::warn!("{rpc}: no port map for {verilog_cid}: {err}");
+                vec![]
+            }),
+        };
+        self.store(
+            &skcd_file_path,
+            inputs,
+            &skcd_stats,
+            &caller,
+            options,
+            generation_options.into(),
+        )
         .await
-        .map_err(|err| Status::internal(err.to_string()))?
-        .map_err(|err: Status| Status::internal(err.to_string()))?;
+    }
+}
 
-        let data = Cursor::new(lib_circuits_wrapper);
+impl SkcdApiServerImpl {
+    /// Fetch(and decompress) a skcd created by this service.
+    ///
+    /// # Errors
+    /// - `not_found` if `skcd_cid` was not created by this service
+    /// - `invalid_argument` if it is larger than `max_garble_skcd_bytes`
+    pub(crate) async fn load_skcd_circuit(&self, skcd_cid: &str) -> Result<SkcdCircuit, Status> {
+        // only our own circuits: they are known to be valid, and their encoding is known
+        let circuit = self.pins.get(skcd_cid)?;
 
-        // TODO error handling, or at least logging
-        let ipfs_result = self
-            .ipfs_client()?
-            .add(data)
+        let mut stream = std::pin::pin!(compression::decompress_stream(
+            self.ipfs.cat_stream(skcd_cid),
+            circuit.content_encoding,
+        ));
+        let mut skcd_buf = vec![];
+        while let Some(chunk) = stream.try_next().await? {
+            if skcd_buf.len() + chunk.len() > self.max_garble_skcd_bytes {
+                return Err(Status::invalid_argument(format!(
+                    "{skcd_cid} is too large: limit is {} bytes",
+                    self.max_garble_skcd_bytes
+                )));
+            }
+            skcd_buf.extend_from_slice(&chunk);
+        }
+
+        tokio::task::spawn_blocking(move || SkcdCircuit::from_skcd_buf(&skcd_buf))
             .await
-            .map_err(|err| Status::unavailable(err.to_string()))?;
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(err.to_string()))
+    }
+
+    /// Garble `circuit`, add the `GarbledCircuit` to IPFS and register it.
+    /// Return its CID and the encoding.
+    pub(crate) async fn garble_and_store(
+        &self,
+        rpc: &'static str,
+        client_key: &str,
+        skcd_cid: &str,
+        circuit: Arc<SkcdCircuit>,
+        seed: [u8; 32],
+        cid_version: interstellarpbapicircuits::CidVersion,
+    ) -> Result<(String, GarbledEncoding), Status> {
+        let cancellation_token = Arc::new(CancellationToken::default());
+        let _cancel_on_drop = CancelOnDrop(cancellation_token.clone());
 
-        let reply = SkcdGenericFromIpfsReply {
-            skcd_cid: ipfs_result.hash,
+        // moved in the task: released when the garbling is really done, even
+        // if this future is dropped before
+        let permit = self
+            .garbling_permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let garbling_start = Instant::now();
+        let garbled = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let result = garble::garble(&circuit, seed, || cancellation_token.is_cancelled());
+            record_if_cancelled(rpc, &cancellation_token, garbling_start);
+
+            result
+        })
+        .await;
+        self.record_cpu_time(client_key, garbling_start.elapsed());
+        let (encoding, tables) = garbled
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let garbled_circuit = GarbledCircuit {
+            skcd_cid: skcd_cid.to_string(),
+            scheme: garble::SCHEME.to_string(),
+            tables: tables.tables,
+            decoding: tables.decoding,
         };
+        let garbled_cid = self
+            .ipfs
+            .add(garbled_circuit.encode_to_vec(), to_cid_version(cid_version))
+            .await?;
+        self.pins.register_garbled(skcd_cid, &garbled_cid).await;
+
+        Ok((garbled_cid, encoding))
+    }
+}
+
+#[tonic::async_trait]
+impl SkcdApi for SkcdApiServerImpl {
+    async fn generate_skcd_display(
+        &self,
+        request: Request<SkcdDisplayRequest>,
+    ) -> Result<Response<SkcdDisplayReply>, Status> {
+        let skcd_cid = self
+            .generate_display(
+                "generate_skcd_display",
+                &request,
+                request.get_ref(),
+                &GenerateOptions::default(),
+            )
+            .await?
+            .skcd_cid;
+
+        let reply = SkcdDisplayReply { skcd_cid };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn generate_skcd_generic_from_ipfs(
+        &self,
+        request: Request<SkcdGenericFromIpfsRequest>,
+    ) -> Result<Response<SkcdGenericFromIpfsReply>, Status> {
+        let skcd_cid = self
+            .generate_generic(
+                "generate_skcd_generic_from_ipfs",
+                &request,
+                request.get_ref(),
+                &GenerateOptions::default(),
+            )
+            .await?
+            .skcd_cid;
+
+        let reply = SkcdGenericFromIpfsReply { skcd_cid };
 
         Ok(Response::new(reply))
     }
@@ -0,0 +1,141 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Optional compression of the skcd blobs stored in IPFS.
+// The encoding is NOT detectable from the blob itself(a skcd.pb.bin CAN start
+// with the zstd magic) so it is recorded next to the circuit cf `PinnedCircuit`
+// and returned to the clients, with the same names as HTTP "Content-Encoding".
+//
+// NOTE: this is only about storage; the transport is compressed by gRPC itself.
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_util::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::path::Path;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWriteExt, BufReader};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tonic::Status;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentEncoding {
+    #[default]
+    Identity,
+    Zstd,
+    Gzip,
+}
+
+impl ContentEncoding {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Identity => "identity",
+            ContentEncoding::Zstd => "zstd",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+/// Compress `src_path` into `dst_path`; streamed ie constant memory.
+///
+/// # Errors
+/// if the files can not be read/written
+pub async fn compress_file(
+    src_path: &Path,
+    dst_path: &Path,
+    encoding: ContentEncoding,
+) -> Result<(), Error> {
+    let src = BufReader::new(tokio::fs::File::open(src_path).await?);
+    let mut encoded = encode(src, encoding);
+    let mut dst = tokio::fs::File::create(dst_path).await?;
+    tokio::io::copy(&mut encoded, &mut dst).await?;
+    // else the last write MAY still be in flight when the file is re-opened
+    dst.flush().await?;
+
+    Ok(())
+}
+
+/// Decompress a stream of chunks eg from `IpfsStorage::cat_stream`
+pub fn decompress_stream<S>(
+    stream: S,
+    encoding: ContentEncoding,
+) -> impl Stream<Item = Result<Bytes, Status>>
+where
+    S: Stream<Item = Result<Bytes, Status>> + Send + 'static,
+{
+    let reader = StreamReader::new(stream.map_err(|status| Error::other(status.to_string())));
+    ReaderStream::new(decode(reader, encoding))
+        .map(|chunk| chunk.map_err(|err| Status::data_loss(format!("decompression: {err}"))))
+}
+
+fn encode<R>(reader: R, encoding: ContentEncoding) -> Pin<Box<dyn AsyncRead + Send>>
+where
+    R: AsyncBufRead + Send + 'static,
+{
+    match encoding {
+        ContentEncoding::Identity => Box::pin(reader),
+        ContentEncoding::Zstd => Box::pin(ZstdEncoder::new(reader)),
+        ContentEncoding::Gzip => Box::pin(GzipEncoder::new(reader)),
+    }
+}
+
+fn decode<R>(reader: R, encoding: ContentEncoding) -> Pin<Box<dyn AsyncRead + Send>>
+where
+    R: AsyncBufRead + Send + 'static,
+{
+    match encoding {
+        ContentEncoding::Identity => Box::pin(reader),
+        ContentEncoding::Zstd => Box::pin(ZstdDecoder::new(reader)),
+        ContentEncoding::Gzip => Box::pin(GzipDecoder::new(reader)),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_compress_decompress_roundtrip() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let src_path = tmp_dir.path().join("skcd.pb.bin");
+        let dst_path = tmp_dir.path().join("skcd.pb.bin.zst");
+        let data: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 7).to_le_bytes())
+            .collect();
+        std::fs::write(&src_path, &data).unwrap();
+
+        for encoding in [ContentEncoding::Zstd, ContentEncoding::Gzip] {
+            compress_file(&src_path, &dst_path, encoding).await.unwrap();
+            let compressed = std::fs::read(&dst_path).unwrap();
+            assert!(compressed.len() < data.len());
+
+            // multiple chunks, like IPFS
+            let chunks: Vec<Result<Bytes, Status>> = compressed
+                .chunks(1000)
+                .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+                .collect();
+            let decompressed: Vec<Bytes> =
+                decompress_stream(futures_util::stream::iter(chunks), encoding)
+                    .try_collect()
+                    .await
+                    .unwrap();
+            assert_eq!(decompressed.concat(), data);
+        }
+    }
+}
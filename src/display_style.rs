@@ -0,0 +1,141 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Colors of a display circuit cf `SkcdDisplayWithOptionsRequest.style`.
+// A display circuit outputs one bit per pixel(ie segment ON/OFF) so a style is
+// a palette indexed by that bit: it does NOT change the circuit nor its CID.
+// Each digit can have its own palette; the renderer picks it with the bboxes.
+//
+// NOT supported: antialias; needs more than one bit per pixel ie support in `lib_circuits`
+
+/// of each pixel output of a display circuit
+pub const BITS_PER_PIXEL: u32 = 1;
+
+/// Colors are 0xRRGGBBAA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayStyle {
+    pub foreground_rgba: u32,
+    pub background_rgba: u32,
+    pub grayscale: bool,
+}
+
+impl Default for DisplayStyle {
+    /// == the monochrome masks: white segments on black
+    fn default() -> Self {
+        Self {
+            foreground_rgba: 0xFFFF_FFFF,
+            background_rgba: 0x0000_00FF,
+            grayscale: false,
+        }
+    }
+}
+
+/// `palette[value]`: the color of a pixel whose output is `value`
+#[must_use]
+pub fn palette(style: &DisplayStyle) -> Vec<u32> {
+    let palette = vec![style.background_rgba, style.foreground_rgba];
+    if style.grayscale {
+        return palette.into_iter().map(to_gray).collect();
+    }
+    palette
+}
+
+/// The colors of ONE digit; None: the one of the `DisplayStyle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DigitColors {
+    pub foreground_rgba: Option<u32>,
+    pub background_rgba: Option<u32>,
+}
+
+/// One palette per digit cf `palette`, in the order of the bboxes; empty if
+/// no digit has its own colors. `style.grayscale` applies to all of them.
+///
+/// # Errors
+/// if there are more `digits_colors` than digits
+pub fn digits_palettes(
+    style: &DisplayStyle,
+    digits_colors: &[DigitColors],
+    nb_digits: usize,
+) -> Result<Vec<Vec<u32>>, String> {
+    if digits_colors.len() > nb_digits {
+        return Err(format!(
+            "{} digits_colors for {nb_digits} digits",
+            digits_colors.len()
+        ));
+    }
+    if digits_colors.is_empty() {
+        return Ok(vec![]);
+    }
+
+    Ok((0..nb_digits)
+        .map(|index| {
+            let colors = digits_colors.get(index).copied().unwrap_or_default();
+            palette(&DisplayStyle {
+                foreground_rgba: colors.foreground_rgba.unwrap_or(style.foreground_rgba),
+                background_rgba: colors.background_rgba.unwrap_or(style.background_rgba),
+                grayscale: style.grayscale,
+            })
+        })
+        .collect())
+}
+
+/// ITU-R BT.601 luma; alpha is kept
+fn to_gray(rgba: u32) -> u32 {
+    let [r, g, b, a] = rgba.to_be_bytes();
+    let luma = (299 * u32::from(r) + 587 * u32::from(g) + 114 * u32::from(b) + 500) / 1000;
+    let luma = u8::try_from(luma).unwrap_or(u8::MAX);
+    u32::from_be_bytes([luma, luma, luma, a])
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_palette() {
+        let style = DisplayStyle {
+            foreground_rgba: 0xFF00_0080,
+            ..Default::default()
+        };
+        assert_eq!(palette(&style), vec![0x0000_00FF, 0xFF00_0080]);
+
+        let style = DisplayStyle {
+            grayscale: true,
+            ..style
+        };
+        assert_eq!(palette(&style), vec![0x0000_00FF, 0x4C4C_4C80]);
+    }
+
+    #[test]
+    fn test_digits_palettes() {
+        let style = DisplayStyle::default();
+        assert!(digits_palettes(&style, &[], 2).unwrap().is_empty());
+
+        let red = DigitColors {
+            foreground_rgba: Some(0xFF00_00FF),
+            background_rgba: None,
+        };
+        assert_eq!(
+            digits_palettes(&style, &[DigitColors::default(), red], 3).unwrap(),
+            vec![
+                vec![0x0000_00FF, 0xFFFF_FFFF],
+                vec![0x0000_00FF, 0xFF00_00FF],
+                vec![0x0000_00FF, 0xFFFF_FFFF],
+            ]
+        );
+
+        assert!(digits_palettes(&style, &[red, red], 1).is_err());
+    }
+}
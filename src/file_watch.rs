@@ -0,0 +1,84 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// Poll `paths` every `interval` and call `on_change` whenever at least one of
+/// them was modified(or appeared/disappeared) since the previous poll.
+///
+/// NOTE: polling instead of inotify & co b/c the typical use case is a k8s
+/// Secret/ConfigMap volume, which is updated via a symlink swap that is NOT
+/// always reported by the OS-level watchers.
+pub fn spawn_watcher<F>(paths: Vec<PathBuf>, interval: Duration, on_change: F) -> JoinHandle<()>
+where
+    F: Fn() + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut last_seen = modified_times(&paths);
+        let mut ticker = tokio::time::interval(interval);
+        // the first tick completes immediately
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let current = modified_times(&paths);
+            if current != last_seen {
+                log::info!("file_watch: change detected in {paths:?}");
+                on_change();
+                last_seen = current;
+            }
+        }
+    })
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| {
+            std::fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_change_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.crt");
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let watcher = spawn_watcher(vec![path.clone()], Duration::from_millis(10), move || {
+            let _ = tx.send(());
+        });
+
+        // let it record the initial state ie "missing"
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+
+        std::fs::write(&path, "renewed").unwrap();
+        tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        watcher.abort();
+    }
+}
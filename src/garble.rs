@@ -0,0 +1,424 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Yao garbling of a `SkcdCircuit`: free-XOR + half-gates
+// cf "Two Halves Make a Whole" <https://eprint.iacr.org/2014/756>
+//
+// - each wire has a 16 bytes label for 0; the label for 1 is XOR `delta`
+// - affine gates(XOR, XNOR, INV, BUF, constants, ...) are free ie no table
+// - the others are all "(a ^ alpha) AND (b ^ beta) ^ gamma": 2 ciphertexts each
+// - H(label, tweak) = SHA-256(label || tweak as u64 LE)[..16]
+// - point-and-permute bit: the lsb of byte 0
+//
+// `evaluate` is the reference evaluator; it MUST match `SCHEME`.
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+
+use crate::skcd::{SkcdCircuit, SkcdGateType, SkcdStats};
+
+/// Stored with each garbled circuit; bump on any change to the format
+pub const SCHEME: &str = "half-gates/free-xor/sha256/v1";
+
+pub const LABEL_SIZE: usize = 16;
+/// per non-free gate
+pub const TABLE_SIZE: usize = 2 * LABEL_SIZE;
+
+/// `is_cancelled` is called every that many gates
+const CANCELLATION_CHECK_INTERVAL: usize = 4096;
+
+/// What the garbler needs to encode the inputs; MUST stay secret: with it
+/// the evaluator could evaluate on any input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GarbledEncoding {
+    pub delta: [u8; LABEL_SIZE],
+    /// one per input
+    pub zero_labels: Vec<[u8; LABEL_SIZE]>,
+}
+
+impl GarbledEncoding {
+    /// The labels to give to the evaluator for `inputs`
+    #[must_use]
+    pub fn encode(&self, inputs: &[bool]) -> Vec<[u8; LABEL_SIZE]> {
+        let delta = u128::from_le_bytes(self.delta);
+        self.zero_labels
+            .iter()
+            .zip(inputs)
+            .map(|(zero_label, &input)| {
+                (u128::from_le_bytes(*zero_label) ^ mask(input, delta)).to_le_bytes()
+            })
+            .collect()
+    }
+}
+
+/// What the evaluator needs, along with the skcd itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GarbledTables {
+    /// `TABLE_SIZE` bytes per non-free gate, in the order of the gates
+    pub tables: Vec<u8>,
+    /// one per output: value = lsb(label) ^ decoding
+    pub decoding: Vec<bool>,
+}
+
+enum GateKind {
+    /// c0 ^ (ca AND a) ^ (cb AND b)
+    Affine { c0: bool, ca: bool, cb: bool },
+    /// ((a ^ alpha) AND (b ^ beta)) ^ gamma
+    AndLike {
+        alpha: bool,
+        beta: bool,
+        gamma: bool,
+    },
+}
+
+impl GateKind {
+    fn new(gate_type: SkcdGateType) -> Self {
+        let f = |a, b| gate_type.eval(a, b);
+        let nb_ones = [(false, false), (true, false), (false, true), (true, true)]
+            .into_iter()
+            .filter(|&(a, b)| f(a, b))
+            .count();
+
+        match nb_ones {
+            // exactly one input pair where the AND is 1: (!alpha, !beta)
+            1 | 3 => {
+                let gamma = nb_ones == 3;
+                let (a, b) = [(false, false), (true, false), (false, true), (true, true)]
+                    .into_iter()
+                    .find(|&(a, b)| f(a, b) != gamma)
+                    .unwrap_or_default();
+                GateKind::AndLike {
+                    alpha: !a,
+                    beta: !b,
+                    gamma,
+                }
+            }
+            _ => {
+                let c0 = f(false, false);
+                GateKind::Affine {
+                    c0,
+                    ca: f(true, false) != c0,
+                    cb: f(false, true) != c0,
+                }
+            }
+        }
+    }
+}
+
+/// Free-XOR: the affine gates(XOR, XNOR, NOT, buffers, constants) need no table
+#[must_use]
+pub fn is_free(gate_type: SkcdGateType) -> bool {
+    matches!(GateKind::new(gate_type), GateKind::Affine { .. })
+}
+
+/// Size of the garbled tables of a circuit, from its stats ie without garbling it.
+/// NOTE: only the tables; the decoding bits and the input labels do NOT
+/// depend on the scheme.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GarbledSizeEstimate {
+    /// XOR + XNOR
+    pub nb_xor_gates: u64,
+    pub nb_free_gates: u64,
+    pub nb_non_free_gates: u64,
+    /// Free-XOR with row reduction(GRR3): 3 labels per non-free gate
+    pub free_xor_bytes: u64,
+    /// Half-gates ie this module: `TABLE_SIZE` per non-free gate
+    pub half_gates_bytes: u64,
+}
+
+impl GarbledSizeEstimate {
+    #[must_use]
+    pub fn new(stats: &SkcdStats) -> Self {
+        let count = |filter: &dyn Fn(SkcdGateType) -> bool| -> u64 {
+            stats
+                .gates_by_type
+                .iter()
+                .filter(|(gate_type, _)| filter(**gate_type))
+                .map(|(_, count)| count)
+                .sum()
+        };
+        let nb_non_free_gates = count(&|gate_type| !is_free(gate_type));
+
+        Self {
+            nb_xor_gates: count(&|gate_type| {
+                matches!(gate_type, SkcdGateType::Xor | SkcdGateType::Xnor)
+            }),
+            nb_free_gates: count(&is_free),
+            nb_non_free_gates,
+            free_xor_bytes: nb_non_free_gates * 3 * LABEL_SIZE as u64,
+            half_gates_bytes: nb_non_free_gates * TABLE_SIZE as u64,
+        }
+    }
+}
+
+/// Garble `circuit`; all the randomness comes from `seed`.
+///
+/// # Errors
+/// - `InvalidData` if a gate uses a wire NOT defined before it
+/// - `Interrupted` if `is_cancelled` returned true
+pub fn garble(
+    circuit: &SkcdCircuit,
+    seed: [u8; 32],
+    is_cancelled: impl Fn() -> bool,
+) -> Result<(GarbledEncoding, GarbledTables), Error> {
+    let mut rng = ChaCha20Rng::from_seed(seed);
+    // lsb 1: the two labels of a wire have different permute bits
+    let delta: u128 = rng.gen::<u128>() | 1;
+
+    let mut labels: Vec<Option<u128>> = vec![None; circuit.nb_wires()];
+    for label in labels.iter_mut().take(circuit.nb_inputs as usize) {
+        *label = Some(rng.gen());
+    }
+
+    let mut tables = vec![];
+    let mut nb_and_gates: u64 = 0;
+    for (index, gate) in circuit.gates.iter().enumerate() {
+        if index % CANCELLATION_CHECK_INTERVAL == 0 && is_cancelled() {
+            return Err(Error::new(ErrorKind::Interrupted, "garbling cancelled"));
+        }
+
+        let zero_label = match GateKind::new(gate.gate_type) {
+            GateKind::Affine { c0, ca, cb } => {
+                let mut zero_label = mask(c0, delta);
+                if ca {
+                    zero_label ^= wire_label(&labels, gate.a)?;
+                }
+                if cb {
+                    zero_label ^= wire_label(&labels, gate.b)?;
+                }
+                zero_label
+            }
+            GateKind::AndLike { alpha, beta, gamma } => {
+                let a0 = wire_label(&labels, gate.a)? ^ mask(alpha, delta);
+                let b0 = wire_label(&labels, gate.b)? ^ mask(beta, delta);
+                let (pa, pb) = (lsb(a0), lsb(b0));
+                let (j, j2) = (2 * nb_and_gates, 2 * nb_and_gates + 1);
+                nb_and_gates += 1;
+
+                // garbler half
+                let tg = hash(a0, j) ^ hash(a0 ^ delta, j) ^ mask(pb, delta);
+                let wg0 = hash(a0, j) ^ mask(pa, tg);
+                // evaluator half
+                let te = hash(b0, j2) ^ hash(b0 ^ delta, j2) ^ a0;
+                let we0 = hash(b0, j2) ^ mask(pb, te ^ a0);
+
+                tables.extend_from_slice(&tg.to_le_bytes());
+                tables.extend_from_slice(&te.to_le_bytes());
+                wg0 ^ we0 ^ mask(gamma, delta)
+            }
+        };
+        labels[gate.o as usize] = Some(zero_label);
+    }
+
+    let decoding = circuit
+        .outputs
+        .iter()
+        .map(|&output| wire_label(&labels, output).map(lsb))
+        .collect::<Result<_, Error>>()?;
+
+    Ok((
+        GarbledEncoding {
+            delta: delta.to_le_bytes(),
+            zero_labels: labels[..circuit.nb_inputs as usize]
+                .iter()
+                .map(|label| label.unwrap_or_default().to_le_bytes())
+                .collect(),
+        },
+        GarbledTables { tables, decoding },
+    ))
+}
+
+/// Evaluate with the labels of the inputs cf `GarbledEncoding::encode`.
+///
+/// # Errors
+/// `InvalidData` if the inputs/tables do NOT match `circuit`
+pub fn evaluate(
+    circuit: &SkcdCircuit,
+    garbled: &GarbledTables,
+    input_labels: &[[u8; LABEL_SIZE]],
+) -> Result<Vec<bool>, Error> {
+    if input_labels.len() != circuit.nb_inputs as usize
+        || garbled.decoding.len() != circuit.outputs.len()
+    {
+        return Err(Error::new(ErrorKind::InvalidData, "garble: size mismatch"));
+    }
+
+    let mut labels: Vec<Option<u128>> = vec![None; circuit.nb_wires()];
+    for (label, input_label) in labels.iter_mut().zip(input_labels) {
+        *label = Some(u128::from_le_bytes(*input_label));
+    }
+
+    let mut tables = garbled.tables.chunks_exact(LABEL_SIZE);
+    let mut next_ciphertext = || -> Result<u128, Error> {
+        tables
+            .next()
+            .and_then(|ciphertext| ciphertext.try_into().ok())
+            .map(u128::from_le_bytes)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "garble: truncated tables"))
+    };
+    let mut nb_and_gates: u64 = 0;
+    for gate in &circuit.gates {
+        let label = match GateKind::new(gate.gate_type) {
+            GateKind::Affine { ca, cb, .. } => {
+                let mut label = 0;
+                if ca {
+                    label ^= wire_label(&labels, gate.a)?;
+                }
+                if cb {
+                    label ^= wire_label(&labels, gate.b)?;
+                }
+                label
+            }
+            GateKind::AndLike { .. } => {
+                let (a, b) = (wire_label(&labels, gate.a)?, wire_label(&labels, gate.b)?);
+                let (tg, te) = (next_ciphertext()?, next_ciphertext()?);
+                let (j, j2) = (2 * nb_and_gates, 2 * nb_and_gates + 1);
+                nb_and_gates += 1;
+
+                let wg = hash(a, j) ^ mask(lsb(a), tg);
+                let we = hash(b, j2) ^ mask(lsb(b), te ^ a);
+                wg ^ we
+            }
+        };
+        labels[gate.o as usize] = Some(label);
+    }
+
+    circuit
+        .outputs
+        .iter()
+        .zip(&garbled.decoding)
+        .map(|(&output, &decoding)| Ok(lsb(wire_label(&labels, output)?) ^ decoding))
+        .collect()
+}
+
+fn wire_label(labels: &[Option<u128>], wire: u32) -> Result<u128, Error> {
+    labels.get(wire as usize).copied().flatten().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("garble: undefined wire {wire}"),
+        )
+    })
+}
+
+fn hash(label: u128, tweak: u64) -> u128 {
+    let digest = Sha256::new()
+        .chain_update(label.to_le_bytes())
+        .chain_update(tweak.to_le_bytes())
+        .finalize();
+    let mut truncated = [0; LABEL_SIZE];
+    truncated.copy_from_slice(&digest[..LABEL_SIZE]);
+    u128::from_le_bytes(truncated)
+}
+
+fn lsb(label: u128) -> bool {
+    label & 1 == 1
+}
+
+fn mask(bit: bool, value: u128) -> u128 {
+    if bit {
+        value
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use crate::skcd::SkcdGate;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_garbled_size_estimate() {
+        let stats = SkcdStats {
+            nb_inputs: 3,
+            nb_outputs: 2,
+            nb_gates: 6,
+            gates_by_type: BTreeMap::from([
+                (SkcdGateType::Xor, 2),
+                (SkcdGateType::Xnor, 1),
+                (SkcdGateType::Inv, 1),
+                (SkcdGateType::And, 1),
+                (SkcdGateType::Or, 1),
+            ]),
+        };
+
+        assert_eq!(
+            GarbledSizeEstimate::new(&stats),
+            GarbledSizeEstimate {
+                nb_xor_gates: 3,
+                nb_free_gates: 4,
+                nb_non_free_gates: 2,
+                free_xor_bytes: 2 * 48,
+                half_gates_bytes: 2 * 32,
+            }
+        );
+    }
+
+    #[test]
+    fn test_garble_evaluate_all_gate_types() {
+        for gate_type in (0..16).filter_map(SkcdGateType::from_i32) {
+            let circuit = SkcdCircuit {
+                nb_inputs: 2,
+                gates: vec![SkcdGate {
+                    a: 0,
+                    b: 1,
+                    o: 2,
+                    gate_type,
+                }],
+                outputs: vec![2],
+            };
+            let (encoding, garbled) = garble(&circuit, [42; 32], || false).unwrap();
+
+            for (a, b) in [(false, false), (true, false), (false, true), (true, true)] {
+                let outputs = evaluate(&circuit, &garbled, &encoding.encode(&[a, b])).unwrap();
+                assert_eq!(
+                    outputs,
+                    vec![gate_type.eval(a, b)],
+                    "{gate_type:?}({a}, {b})"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_garble_evaluate_full_adder() {
+        // inputs: a=0, b=1, carry_in=2; outputs: sum, carry_out
+        let gate = |a, b, o, gate_type| SkcdGate { a, b, o, gate_type };
+        let circuit = SkcdCircuit {
+            nb_inputs: 3,
+            gates: vec![
+                gate(0, 1, 3, SkcdGateType::Xor),
+                gate(3, 2, 4, SkcdGateType::Xor),
+                gate(0, 1, 5, SkcdGateType::And),
+                gate(3, 2, 6, SkcdGateType::And),
+                gate(5, 6, 7, SkcdGateType::Or),
+            ],
+            outputs: vec![4, 7],
+        };
+        let (encoding, garbled) = garble(&circuit, [7; 32], || false).unwrap();
+        // XOR are free
+        assert_eq!(garbled.tables.len(), 3 * TABLE_SIZE);
+
+        for inputs in 0..8u32 {
+            let bits: Vec<bool> = (0..3).map(|i| inputs >> i & 1 == 1).collect();
+            let sum = bits.iter().filter(|&&bit| bit).count();
+            let outputs = evaluate(&circuit, &garbled, &encoding.encode(&bits)).unwrap();
+            assert_eq!(outputs, vec![sum % 2 == 1, sum >= 2]);
+        }
+    }
+}
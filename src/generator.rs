@@ -0,0 +1,111 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Runs the lib_circuits generations in a subprocess(cf src/bin/generate_skcd.rs
+// and `--generator-path`) so that they can be stopped: lib_circuits has no
+// cancellation point inside a generation, and a blocking thread can NOT be
+// killed. The subprocess is killed when the future is dropped ie on timeout
+// and when the request is abandoned.
+
+use lib_circuits_wrapper::ffi::GenerationOptions;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// cf `GenerateDisplaySkcdToFile`
+///
+/// # Errors
+/// if the generator could NOT run, or failed eg invalid bboxes
+pub async fn generate_display(
+    generator_path: &Path,
+    width: u32,
+    height: u32,
+    digits_bboxes: &[f32],
+    output_path: &Path,
+    options: &GenerationOptions,
+) -> Result<(), String> {
+    let mut args = common_args(output_path, options.deterministic);
+    args.extend([
+        "display".into(),
+        "--width".into(),
+        width.to_string().into(),
+        "--height".into(),
+        height.to_string().into(),
+    ]);
+    if !digits_bboxes.is_empty() {
+        // NOTE: `to_string` is the shortest repr that round-trips ie exact
+        let digits_bboxes = digits_bboxes
+            .iter()
+            .map(f32::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        // "=": b/c the first value may be negative
+        args.push(format!("--digits-bboxes={digits_bboxes}").into());
+    }
+
+    run(generator_path, args).await
+}
+
+/// cf `GenerateGenericSkcdToFile`
+///
+/// # Errors
+/// if the generator could NOT run, or failed eg invalid Verilog
+pub async fn generate_generic(
+    generator_path: &Path,
+    verilog_path: &Path,
+    output_path: &Path,
+    options: &GenerationOptions,
+) -> Result<(), String> {
+    let mut args = common_args(output_path, options.deterministic);
+    args.extend(["generic".into(), "--verilog".into(), verilog_path.into()]);
+
+    run(generator_path, args).await
+}
+
+/// The args before the subcommand
+fn common_args(output_path: &Path, deterministic: bool) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["--output".into(), output_path.into()];
+    if deterministic {
+        args.push("--deterministic".into());
+    }
+
+    args
+}
+
+async fn run(generator_path: &Path, args: Vec<OsString>) -> Result<(), String> {
+    let child = Command::new(generator_path)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        // lib_circuits logs there; the error is the last line
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("could not run {}: {err}", generator_path.display()))?;
+
+    let output = child
+        .wait_with_output()
+        .await
+        .map_err(|err| format!("generator: {err}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(match stderr.lines().last() {
+            Some(error) => format!("generation failed: {error}"),
+            None => format!("generation failed: {}", output.status),
+        });
+    }
+
+    Ok(())
+}
@@ -0,0 +1,324 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Long-lived IPFS clients, shared by all the requests.
+// Each `IpfsClient` wraps a hyper Client so the connections are pooled.
+// Each operation is retried with exponential backoff, and moves to the next
+// endpoint(if several are configured) on failure.
+// The returned CIDs are verified against `cid::compute_cid`.
+
+use crate::cid::{self, CidVersion};
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_util::TryStreamExt;
+use ipfs_api_backend_hyper::{request, IpfsApi, IpfsClient, TryFromUri};
+use std::future::Future;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::Status;
+
+#[derive(Debug, Clone)]
+pub struct IpfsConfig {
+    /// eg "/ip4/127.0.0.1/tcp/5001"
+    /// Tried in order: the first one is used until it fails.
+    pub multiaddrs: Vec<String>,
+    pub cat_timeout: Duration,
+    pub add_timeout: Duration,
+    /// number of retries after the first attempt
+    pub max_retries: u32,
+    /// doubled after each failed attempt
+    pub initial_backoff: Duration,
+}
+
+impl IpfsConfig {
+    #[must_use]
+    pub fn new(multiaddrs: Vec<String>) -> Self {
+        Self {
+            multiaddrs,
+            cat_timeout: Duration::from_secs(30),
+            add_timeout: Duration::from_secs(60),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Returned by each attempt; only `Retriable` errors are retried
+enum AttemptError {
+    Retriable(String),
+    Fatal(Status),
+}
+
+struct Endpoint {
+    multiaddr: String,
+    client: IpfsClient,
+}
+
+pub struct IpfsStorage {
+    endpoints: Vec<Endpoint>,
+    config: IpfsConfig,
+    /// index in `endpoints` of the last one that worked
+    preferred: AtomicUsize,
+}
+
+impl IpfsStorage {
+    /// # Errors
+    /// if `multiaddrs` is empty or contains an invalid multiaddr
+    pub fn new(config: IpfsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        if config.multiaddrs.is_empty() {
+            return Err("IpfsStorage: at least one multiaddr is required".into());
+        }
+
+        let endpoints = config
+            .multiaddrs
+            .iter()
+            .map(|multiaddr| {
+                log::info!("ipfs: client for {multiaddr}");
+                Ok(Endpoint {
+                    multiaddr: multiaddr.clone(),
+                    client: IpfsClient::from_multiaddr_str(multiaddr)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+        Ok(Self {
+            endpoints,
+            config,
+            preferred: AtomicUsize::new(0),
+        })
+    }
+
+    /// Add `data`, pin it, and return its CID.
+    /// The CID returned by the node is checked against the one computed locally.
+    ///
+    /// # Errors
+    /// - `unavailable` if all the attempts failed
+    /// - `data_loss` if the CIDs do NOT match
+    pub async fn add(&self, data: Vec<u8>, cid_version: CidVersion) -> Result<String, Status> {
+        let expected_cid = cid::compute_cid(&data[..], cid_version)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        // Arc: re-used by each attempt without copying
+        let data: Arc<[u8]> = data.into();
+
+        let cid = self
+            .with_retries("add", self.config.add_timeout, |client| {
+                let data = data.clone();
+                async move {
+                    client
+                        .add_with_options(Cursor::new(data), pinned_add_request(cid_version))
+                        .await
+                        .map(|add_response| add_response.hash)
+                        .map_err(|err| AttemptError::Retriable(err.to_string()))
+                }
+            })
+            .await?;
+
+        check_cid(&cid, &expected_cid)
+    }
+
+    /// Same as `add` but stream the content of `path`, so that large circuits
+    /// are never fully loaded in memory; the CID is computed by the node as
+    /// the body is received.
+    /// Each attempt re-opens the file.
+    ///
+    /// # Errors
+    /// - `internal` if the file can not be opened
+    /// - `unavailable` if all the attempts failed
+    /// - `data_loss` if the CIDs do NOT match
+    pub async fn add_file(&self, path: &Path, cid_version: CidVersion) -> Result<String, Status> {
+        // NOT in parallel with the upload: that would read the file twice concurrently
+        // for little gain; it is local, the upload is NOT
+        let path_owned = path.to_path_buf();
+        let expected_cid = tokio::task::spawn_blocking(move || {
+            cid::compute_cid(
+                BufReader::new(std::fs::File::open(path_owned)?),
+                cid_version,
+            )
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(|err| Status::internal(err.to_string()))?;
+
+        let cid = self
+            .with_retries("add_file", self.config.add_timeout, |client| {
+                let file = std::fs::File::open(path);
+                async move {
+                    // NOTE: the multipart body reads the file in small chunks
+                    // with blocking reads; acceptable for a local tmp file
+                    let file =
+                        file.map_err(|err| AttemptError::Fatal(Status::internal(err.to_string())))?;
+                    client
+                        .add_with_options(file, pinned_add_request(cid_version))
+                        .await
+                        .map(|add_response| add_response.hash)
+                        .map_err(|err| AttemptError::Retriable(err.to_string()))
+                }
+            })
+            .await?;
+
+        check_cid(&cid, &expected_cid)
+    }
+
+    /// Get the content of `cid`; stops as soon as more than `max_bytes` are received.
+    ///
+    /// # Errors
+    /// - `invalid_argument` if the content is larger than `max_bytes`
+    /// - `unavailable` if all the attempts failed
+    pub async fn cat(&self, cid: &str, max_bytes: usize) -> Result<Vec<u8>, Status> {
+        self.with_retries("cat", self.config.cat_timeout, |client| {
+            let cid = cid.to_string();
+            async move {
+                // NOT try_concat: we MUST stop as soon as the limit is reached
+                // dropping the stream aborts the download
+                let mut stream = client.cat(&cid);
+                let mut buf = vec![];
+                while let Some(chunk) = stream
+                    .try_next()
+                    .await
+                    .map_err(|err| AttemptError::Retriable(err.to_string()))?
+                {
+                    if buf.len() + chunk.len() > max_bytes {
+                        return Err(AttemptError::Fatal(Status::invalid_argument(format!(
+                            "{cid} is too large: limit is {max_bytes} bytes"
+                        ))));
+                    }
+                    buf.extend_from_slice(&chunk);
+                }
+
+                Ok(buf)
+            }
+        })
+        .await
+    }
+
+    /// Remove the (recursive) pin on `cid`; the blocks are then removed by the
+    /// IPFS node's own GC.
+    /// NOT pinned is a success ie idempotent, so that an interrupted
+    /// `PinManager::unpin` can be retried.
+    ///
+    /// # Errors
+    /// `unavailable` if all the attempts failed
+    pub async fn pin_rm(&self, cid: &str) -> Result<(), Status> {
+        self.with_retries("pin_rm", self.config.add_timeout, |client| {
+            let cid = cid.to_string();
+            async move {
+                match client.pin_rm(&cid, true).await {
+                    Ok(_) => Ok(()),
+                    // kubo: "not pinned or pinned indirectly"
+                    Err(err) if err.to_string().contains("not pinned") => Ok(()),
+                    Err(err) => Err(AttemptError::Retriable(err.to_string())),
+                }
+            }
+        })
+        .await
+    }
+
+    /// Stream the content of `cid`, as received from the node.
+    /// NOT retried: once chunks were yielded we can not transparently resume;
+    /// the client SHOULD retry the whole download.
+    pub fn cat_stream(&self, cid: &str) -> impl Stream<Item = Result<Bytes, Status>> {
+        let endpoint = &self.endpoints[self.preferred.load(Ordering::Relaxed)];
+        let client = endpoint.client.clone();
+        let cid = cid.to_string();
+
+        async_stream::try_stream! {
+            let mut stream = client.cat(&cid);
+            while let Some(chunk) = stream
+                .try_next()
+                .await
+                .map_err(|err| Status::unavailable(format!("ipfs: cat {cid} failed: {err}")))?
+            {
+                yield chunk;
+            }
+        }
+    }
+
+    async fn with_retries<T, F, Fut>(
+        &self,
+        operation: &str,
+        timeout: Duration,
+        attempt_fn: F,
+    ) -> Result<T, Status>
+    where
+        F: Fn(IpfsClient) -> Fut,
+        Fut: Future<Output = Result<T, AttemptError>>,
+    {
+        let mut backoff = self.config.initial_backoff;
+        let mut last_error = String::new();
+
+        for attempt in 0..=self.config.max_retries {
+            let index =
+                (self.preferred.load(Ordering::Relaxed) + attempt as usize) % self.endpoints.len();
+            let endpoint = &self.endpoints[index];
+
+            match tokio::time::timeout(timeout, attempt_fn(endpoint.client.clone())).await {
+                Ok(Ok(result)) => {
+                    self.preferred.store(index, Ordering::Relaxed);
+                    return Ok(result);
+                }
+                Ok(Err(AttemptError::Fatal(status))) => return Err(status),
+                Ok(Err(AttemptError::Retriable(err))) => last_error = err,
+                Err(_) => last_error = format!("timeout after {timeout:?}"),
+            }
+            log::warn!(
+                "ipfs: {operation} on {} failed(attempt {}): {last_error}",
+                endpoint.multiaddr,
+                attempt + 1
+            );
+
+            if attempt < self.config.max_retries {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+
+        Err(Status::unavailable(format!(
+            "ipfs: {operation} failed: {last_error}"
+        )))
+    }
+}
+
+// NOTE: "pin" defaults to true on kubo but be explicit b/c the lifecycle is
+// managed by `PinManager`
+// "raw_leaves" is already the default with CIDv1 but MUST match `cid::compute_cid`
+fn pinned_add_request(cid_version: CidVersion) -> request::Add<'static> {
+    match cid_version {
+        CidVersion::V0 => request::Add {
+            pin: Some(true),
+            ..Default::default()
+        },
+        CidVersion::V1 => request::Add {
+            pin: Some(true),
+            cid_version: Some(1),
+            raw_leaves: Some(true),
+            ..Default::default()
+        },
+    }
+}
+
+/// The node may be misconfigured(eg a different chunker) or compromised;
+/// in both cases the CID we would return could NOT be trusted.
+fn check_cid(cid: &str, expected_cid: &str) -> Result<String, Status> {
+    if cid != expected_cid {
+        log::error!("ipfs: CID mismatch: node returned {cid}, expected {expected_cid}");
+        return Err(Status::data_loss(format!(
+            "ipfs: CID mismatch: node returned {cid}, expected {expected_cid}"
+        )));
+    }
+
+    Ok(cid.to_string())
+}
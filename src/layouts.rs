@@ -0,0 +1,226 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Display layouts: compute the `digits_bboxes` of a display circuit instead of
+// hard-coding them client side cf `SkcdExtApi::ListLayouts`.
+//
+// The digits are on a grid of cells, the last row centered(eg "0" on a pinpad);
+// each bbox is centered in its cell.
+// Coordinates are normalized ie in [0, 1], 4 per digit: lower left (x, y) then
+// upper right (x, y), like `SkcdDisplayRequest.digits_bboxes`.
+// The first row is at the top ie largest y.
+
+/// `lib_circuits` draws each digit with its own segments; NOT worth more
+pub const MAX_DIGITS: u32 = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LayoutParams {
+    pub nb_digits: u32,
+    /// digits per row; 0: all on one row
+    pub nb_columns: u32,
+    /// fraction of the width, on each side
+    pub margin_x: f32,
+    /// fraction of the height, on each side
+    pub margin_y: f32,
+    /// fraction of each cell left empty between the columns
+    pub spacing_x: f32,
+    /// fraction of each cell left empty between the rows
+    pub spacing_y: f32,
+    /// width/height of each digit, in pixels; 0: fill the cell
+    pub digit_aspect_ratio: f32,
+}
+
+pub struct LayoutPreset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub params: LayoutParams,
+}
+
+pub const PRESETS: &[LayoutPreset] = &[
+    LayoutPreset {
+        name: "2-digits",
+        description: "2 digits on one row eg a message",
+        // == the bboxes used by the integration tests
+        params: LayoutParams {
+            nb_digits: 2,
+            nb_columns: 0,
+            margin_x: 0.2,
+            margin_y: 0.1,
+            spacing_x: 1.0 / 3.0,
+            spacing_y: 0.0,
+            digit_aspect_ratio: 0.0,
+        },
+    },
+    LayoutPreset {
+        name: "4-digits-otp",
+        description: "4 digits on one row eg a one time code",
+        params: LayoutParams {
+            nb_digits: 4,
+            nb_columns: 0,
+            margin_x: 0.1,
+            margin_y: 0.1,
+            spacing_x: 0.2,
+            spacing_y: 0.0,
+            digit_aspect_ratio: 0.0,
+        },
+    },
+    LayoutPreset {
+        name: "6-digits",
+        description: "6 digits on one row",
+        params: LayoutParams {
+            nb_digits: 6,
+            nb_columns: 0,
+            margin_x: 0.05,
+            margin_y: 0.1,
+            spacing_x: 0.2,
+            spacing_y: 0.0,
+            digit_aspect_ratio: 0.0,
+        },
+    },
+    LayoutPreset {
+        name: "pinpad-10-keys",
+        description: "10 keys: 3 rows of 3, then 1 centered",
+        params: LayoutParams {
+            nb_digits: 10,
+            nb_columns: 3,
+            margin_x: 0.1,
+            margin_y: 0.05,
+            spacing_x: 0.2,
+            spacing_y: 0.2,
+            digit_aspect_ratio: 0.0,
+        },
+    },
+];
+
+#[must_use]
+pub fn preset(name: &str) -> Option<&'static LayoutPreset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+/// `width`/`height` in pixels, only used for `digit_aspect_ratio`.
+///
+/// # Errors
+/// the reason why `params` is NOT valid
+#[allow(clippy::cast_precision_loss)]
+pub fn compute_bboxes(width: u32, height: u32, params: &LayoutParams) -> Result<Vec<f32>, String> {
+    validate(width, height, params)?;
+
+    let nb_columns = match params.nb_columns {
+        0 => params.nb_digits,
+        nb_columns => nb_columns.min(params.nb_digits),
+    };
+    let nb_rows = params.nb_digits.div_ceil(nb_columns);
+    let cell_width = (1.0 - 2.0 * params.margin_x) / nb_columns as f32;
+    let cell_height = (1.0 - 2.0 * params.margin_y) / nb_rows as f32;
+
+    let mut digit_width = cell_width * (1.0 - params.spacing_x);
+    let mut digit_height = cell_height * (1.0 - params.spacing_y);
+    if params.digit_aspect_ratio > 0.0 {
+        // shrink the dimension that is too large, in pixels
+        let aspect_ratio = (digit_width * width as f32) / (digit_height * height as f32);
+        if aspect_ratio > params.digit_aspect_ratio {
+            digit_width *= params.digit_aspect_ratio / aspect_ratio;
+        } else {
+            digit_height *= aspect_ratio / params.digit_aspect_ratio;
+        }
+    }
+
+    let mut bboxes = Vec::with_capacity(4 * params.nb_digits as usize);
+    for digit in 0..params.nb_digits {
+        let (row, column) = (digit / nb_columns, digit % nb_columns);
+        let nb_in_row = nb_columns.min(params.nb_digits - row * nb_columns);
+        // center the last row if it is NOT full
+        let row_offset = (nb_columns - nb_in_row) as f32 * cell_width / 2.0;
+
+        let center_x = params.margin_x + row_offset + (column as f32 + 0.5) * cell_width;
+        let center_y = 1.0 - params.margin_y - (row as f32 + 0.5) * cell_height;
+        bboxes.extend_from_slice(&[
+            center_x - digit_width / 2.0,
+            center_y - digit_height / 2.0,
+            center_x + digit_width / 2.0,
+            center_y + digit_height / 2.0,
+        ]);
+    }
+
+    Ok(bboxes)
+}
+
+fn validate(width: u32, height: u32, params: &LayoutParams) -> Result<(), String> {
+    if !(1..=MAX_DIGITS).contains(&params.nb_digits) {
+        return Err(format!("nb_digits MUST be in [1, {MAX_DIGITS}]"));
+    }
+    if !(0.0..0.5).contains(&params.margin_x) || !(0.0..0.5).contains(&params.margin_y) {
+        return Err("margins MUST be in [0, 0.5)".to_string());
+    }
+    if !(0.0..1.0).contains(&params.spacing_x) || !(0.0..1.0).contains(&params.spacing_y) {
+        return Err("spacings MUST be in [0, 1)".to_string());
+    }
+    if !params.digit_aspect_ratio.is_finite() || params.digit_aspect_ratio < 0.0 {
+        return Err("digit_aspect_ratio MUST be >= 0".to_string());
+    }
+    if params.digit_aspect_ratio > 0.0 && (width == 0 || height == 0) {
+        return Err("digit_aspect_ratio requires width and height".to_string());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn assert_approx_eq(left: &[f32], right: &[f32]) {
+        assert_eq!(left.len(), right.len());
+        for (l, r) in left.iter().zip(right) {
+            assert!((l - r).abs() < 1e-5, "{left:?} != {right:?}");
+        }
+    }
+
+    #[test]
+    fn test_preset_2_digits() {
+        let bboxes = compute_bboxes(224, 96, &preset("2-digits").unwrap().params).unwrap();
+
+        assert_approx_eq(&bboxes, &[0.25, 0.1, 0.45, 0.9, 0.55, 0.1, 0.75, 0.9]);
+    }
+
+    #[test]
+    fn test_preset_pinpad_last_row_centered() {
+        let bboxes = compute_bboxes(300, 400, &preset("pinpad-10-keys").unwrap().params).unwrap();
+        assert_eq!(bboxes.len(), 4 * 10);
+
+        // "0" is under "8" ie the middle column, on the lowest row:
+        // same x(even indices), y one row lower(odd indices)
+        assert_approx_eq(
+            &bboxes[36..],
+            &bboxes[28..32]
+                .iter()
+                .enumerate()
+                .map(|(i, v)| if i % 2 == 0 { *v } else { v - 0.225 })
+                .collect::<Vec<_>>(),
+        );
+    }
+
+    #[test]
+    fn test_digit_aspect_ratio() {
+        let params = LayoutParams {
+            digit_aspect_ratio: 0.5,
+            ..preset("4-digits-otp").unwrap().params
+        };
+        let bboxes = compute_bboxes(400, 100, &params).unwrap();
+
+        let (digit_width, digit_height) = (bboxes[2] - bboxes[0], bboxes[3] - bboxes[1]);
+        assert!((digit_width * 400.0 / (digit_height * 100.0) - 0.5).abs() < 1e-5);
+    }
+}
@@ -22,4 +22,23 @@
 #![warn(clippy::panic)]
 #![warn(clippy::unwrap_used)]
 
+pub mod auth;
+pub mod cid;
+pub mod circuit_cache;
+pub mod circuits_ext_routes;
 pub mod circuits_routes;
+pub mod compression;
+pub mod display_style;
+pub mod file_watch;
+pub mod garble;
+pub mod generator;
+pub mod ipfs;
+pub mod layouts;
+pub mod manifest;
+pub mod metrics;
+pub mod pinning;
+pub mod port_map;
+pub mod rate_limit;
+pub mod skcd;
+pub mod tls;
+pub mod yosys;
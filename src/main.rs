@@ -12,12 +12,21 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use api_circuits::{
+    auth, circuit_cache, circuits_ext_routes, circuits_routes, file_watch, ipfs, manifest, metrics,
+    pinning, rate_limit, tls,
+};
 use clap::Parser;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tonic::codec::CompressionEncoding;
+use tonic::codegen::InterceptedService;
 use tonic::transport::Server;
 use tonic_web::GrpcWebLayer;
 
-mod circuits_routes;
-
 /// Simple program to greet a person
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -27,12 +36,157 @@ struct Args {
     bind_addr_port: String,
 
     /// Where to reach the IPFS node
+    /// Comma separated list for failover: the next one is used when one fails.
     #[clap(
         long,
         default_value = "/ip4/127.0.0.1/tcp/5001",
-        env = "IPFS_SERVER_MULTIADDR"
+        env = "IPFS_SERVER_MULTIADDR",
+        value_delimiter = ','
+    )]
+    ipfs_server_multiaddr: Vec<String>,
+
+    /// Timeout for each attempt to get a file(ie the Verilog) from IPFS
+    #[clap(long, default_value = "30")]
+    ipfs_cat_timeout_secs: u64,
+
+    /// Timeout for each attempt to add a file(ie the skcd) to IPFS
+    #[clap(long, default_value = "60")]
+    ipfs_add_timeout_secs: u64,
+
+    /// Number of retries(with exponential backoff) for each IPFS operation
+    #[clap(long, default_value = "3")]
+    ipfs_max_retries: u32,
+
+    /// PEM certificate chain; if set the server terminates TLS itself
+    /// (instead of relying on eg envoy in front)
+    #[clap(long, env = "TLS_CERT_PATH", requires = "tls_key_path")]
+    tls_cert_path: Option<PathBuf>,
+
+    /// PEM private key matching `tls_cert_path`
+    #[clap(long, env = "TLS_KEY_PATH", requires = "tls_cert_path")]
+    tls_key_path: Option<PathBuf>,
+
+    /// PEM CA(s) used to verify the clients certificates ie enable mutual-TLS
+    #[clap(long, env = "TLS_CLIENT_CA_PATH", requires = "tls_cert_path")]
+    tls_client_ca_path: Option<PathBuf>,
+
+    /// How often to check the TLS files for changes; they are reloaded without restart
+    #[clap(long, default_value = "30")]
+    tls_reload_interval_secs: u64,
+
+    /// JSON: API keys and JWT settings cf `auth::AuthConfig`
+    /// If NOT set, all the requests are accepted(anonymous) except the admin
+    /// ones eg `UnpinCircuit`
+    #[clap(long, env = "AUTH_CONFIG_PATH")]
+    auth_config_path: Option<PathBuf>,
+
+    /// JSON: per-client rate limits and daily CPU quotas cf `rate_limit::RateLimitConfig`
+    /// The file is watched and changes are applied without restart.
+    /// If NOT set, there is no limit
+    #[clap(long, env = "RATE_LIMIT_CONFIG_PATH")]
+    rate_limit_config_path: Option<PathBuf>,
+
+    /// How often to check the rate limit config for changes
+    #[clap(long, default_value = "10")]
+    rate_limit_reload_interval_secs: u64,
+
+    /// generic circuits: max size of the Verilog downloaded from IPFS
+    #[clap(long, default_value = "1048576")]
+    max_verilog_bytes: usize,
+
+    /// generic circuits: max wall time for the synthesis(yosys/abc)
+    #[clap(long, default_value = "300")]
+    max_synthesis_secs: u64,
+
+    /// generic circuits: the yosys binary, run on the untrusted Verilog eg by
+    /// `ValidateVerilog`; MUST be the same version as the one of lib_circuits
+    #[clap(long, env = "YOSYS_PATH", default_value = "yosys")]
+    yosys_path: PathBuf,
+
+    /// the `generate_skcd` binary(installed along this one); each generation
+    /// runs in its own subprocess, killed on timeout/cancellation
+    #[clap(long, env = "GENERATOR_PATH", default_value = "generate_skcd")]
+    generator_path: PathBuf,
+
+    /// generic circuits: max number of gates of the resulting circuit
+    #[clap(long, default_value = "10000000")]
+    max_gate_count: u64,
+
+    /// `GenerateOptions.inline_skcd`: larger circuits are NOT returned inline,
+    /// the clients then use `DownloadSkcd`
+    #[clap(long, default_value = "3145728")]
+    max_inline_skcd_bytes: u64,
+
+    /// `GarbleSkcd`: max size of the(decompressed) skcd, which is loaded in memory
+    #[clap(long, default_value = "67108864")]
+    max_garble_skcd_bytes: usize,
+
+    /// `GenerateAndGarbleSkcdDisplay`: number of display circuits kept in memory;
+    /// 0 to disable
+    #[clap(long, default_value = "16")]
+    display_cache_capacity: usize,
+
+    /// `GarbleSkcd`/`GenerateAndGarbleSkcdDisplay`: max number of garblings
+    /// running at once(each uses a core); the others wait
+    #[clap(long, default_value = "4")]
+    max_concurrent_garblings: usize,
+
+    /// `GenerateSkcdDisplayBatch`: max number of generations running at
+    /// once, across all the batches; the others wait
+    #[clap(long, default_value = "4")]
+    max_concurrent_batch_generations: usize,
+
+    /// All the generations are deterministic ie identical inputs yield the
+    /// same CID. Else only those with `GenerateOptions.deterministic`
+    #[clap(long)]
+    deterministic: bool,
+
+    /// PKCS#8 PEM Ed25519 key used to sign the provenance manifests
+    /// eg "openssl genpkey -algorithm ed25519"
+    /// If NOT set, a random key is used ie the manifests can NOT be verified
+    /// after a restart
+    #[clap(long, env = "MANIFEST_SIGNING_KEY_PATH")]
+    manifest_signing_key_path: Option<PathBuf>,
+
+    /// JSON: the circuits created by this service cf `pinning::PinManager`
+    /// If NOT set, the list is lost on restart(but the circuits stay pinned)
+    #[clap(long, env = "CIRCUITS_REGISTRY_PATH")]
+    circuits_registry_path: Option<PathBuf>,
+
+    /// Unpin the circuits after that many seconds; if NOT set they stay pinned
+    /// until `UnpinCircuit`
+    #[clap(long)]
+    pin_ttl_secs: Option<u64>,
+
+    /// How often to look for circuits older than `pin_ttl_secs`
+    #[clap(long, default_value = "3600")]
+    pin_gc_interval_secs: u64,
+
+    /// IPFS Pinning Service API endpoint eg "https://api.pinata.cloud/psa"
+    /// If set, every circuit is ALSO pinned there
+    #[clap(
+        long,
+        env = "REMOTE_PINNING_ENDPOINT",
+        requires = "remote_pinning_access_token"
     )]
-    ipfs_server_multiaddr: String,
+    remote_pinning_endpoint: Option<String>,
+
+    /// Bearer token for `remote_pinning_endpoint`
+    #[clap(
+        long,
+        env = "REMOTE_PINNING_ACCESS_TOKEN",
+        requires = "remote_pinning_endpoint"
+    )]
+    remote_pinning_access_token: Option<String>,
+
+    /// allow a http(ie NOT https) `remote_pinning_endpoint`; the access token
+    /// is then sent in clear. Only for tests or a service on localhost
+    #[clap(long, env = "REMOTE_PINNING_ALLOW_HTTP")]
+    remote_pinning_allow_http: bool,
+
+    /// address:port for the Prometheus "/metrics" endpoint; disabled if not set
+    #[clap(long, env = "METRICS_BIND_ADDR_PORT")]
+    metrics_bind_addr_port: Option<SocketAddr>,
 }
 
 // TODO DRY server creation with the tests
@@ -43,23 +197,164 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let circuits_api = circuits_routes::SkcdApiServerImpl {
-        ipfs_server_multiaddr: args.ipfs_server_multiaddr,
+    let ipfs = ipfs::IpfsStorage::new(ipfs::IpfsConfig {
+        cat_timeout: Duration::from_secs(args.ipfs_cat_timeout_secs),
+        add_timeout: Duration::from_secs(args.ipfs_add_timeout_secs),
+        max_retries: args.ipfs_max_retries,
+        ..ipfs::IpfsConfig::new(args.ipfs_server_multiaddr)
+    })?;
+    let ipfs = Arc::new(ipfs);
+    let remote_pinning = match (
+        args.remote_pinning_endpoint,
+        args.remote_pinning_access_token,
+    ) {
+        (Some(endpoint), Some(access_token)) => Some(pinning::RemotePinningService::new(
+            endpoint,
+            access_token,
+            args.remote_pinning_allow_http,
+        )?),
+        _ => None,
     };
-    let circuits_api =
-        circuits_routes::interstellarpbapicircuits::skcd_api_server::SkcdApiServer::new(
-            circuits_api,
+    let pins = Arc::new(pinning::PinManager::new(
+        ipfs.clone(),
+        remote_pinning,
+        args.circuits_registry_path,
+    )?);
+    if let Some(pin_ttl_secs) = args.pin_ttl_secs {
+        pins.clone().spawn_ttl_gc(
+            Duration::from_secs(pin_ttl_secs),
+            Duration::from_secs(args.pin_gc_interval_secs),
         );
+    }
 
-    let addr = args.bind_addr_port.parse().unwrap();
-    println!("Server listening on {addr}");
+    let mut circuits_api = circuits_routes::SkcdApiServerImpl::new(ipfs);
+    circuits_api.pins = pins;
+    circuits_api.max_inline_skcd_bytes = args.max_inline_skcd_bytes;
+    circuits_api.deterministic = args.deterministic;
+    circuits_api.max_garble_skcd_bytes = args.max_garble_skcd_bytes;
+    circuits_api.yosys_path = args.yosys_path;
+    circuits_api.generator_path = args.generator_path;
+    circuits_api.display_cache = Arc::new(circuit_cache::DisplayCircuitCache::new(
+        args.display_cache_capacity,
+    ));
+    circuits_api.garbling_permits = Arc::new(tokio::sync::Semaphore::new(
+        // 0 would block all the garblings forever
+        args.max_concurrent_garblings.max(1),
+    ));
+    circuits_api.batch_generation_permits = Arc::new(tokio::sync::Semaphore::new(
+        args.max_concurrent_batch_generations.max(1),
+    ));
+    circuits_api.manifest_signer = Arc::new(match &args.manifest_signing_key_path {
+        Some(manifest_signing_key_path) => {
+            manifest::ManifestSigner::from_pem_file(manifest_signing_key_path)?
+        }
+        None => {
+            log::warn!("no --manifest-signing-key-path: using a random key");
+            manifest::ManifestSigner::ephemeral()
+        }
+    });
+    log::info!(
+        "manifests are signed with public key {}",
+        circuits_api.manifest_signer.public_key_hex()
+    );
+    circuits_api.generic_limits = circuits_routes::GenericCircuitLimits {
+        max_verilog_bytes: args.max_verilog_bytes,
+        max_synthesis_duration: Duration::from_secs(args.max_synthesis_secs),
+        max_gate_count: args.max_gate_count,
+    };
+    if let Some(rate_limit_config_path) = args.rate_limit_config_path {
+        let rate_limiter = Arc::new(rate_limit::RateLimiter::new(
+            rate_limit::RateLimitConfig::from_file(&rate_limit_config_path)?,
+        ));
+
+        let rate_limiter_watched = rate_limiter.clone();
+        file_watch::spawn_watcher(
+            vec![rate_limit_config_path.clone()],
+            Duration::from_secs(args.rate_limit_reload_interval_secs),
+            move || match rate_limit::RateLimitConfig::from_file(&rate_limit_config_path) {
+                Ok(config) => rate_limiter_watched.set_config(config),
+                Err(err) => {
+                    log::error!("rate_limit: reload failed, keeping the previous config: {err}");
+                }
+            },
+        );
+
+        circuits_api.rate_limiter = Some(rate_limiter);
+    }
+    let authenticator = match &args.auth_config_path {
+        Some(auth_config_path) => Some(Arc::new(auth::Authenticator::from_config_file(
+            auth_config_path,
+        )?)),
+        None => {
+            log::warn!("no --auth-config-path: authentication is DISABLED");
+            None
+        }
+    };
+    let auth_interceptor = auth::AuthInterceptor::new(authenticator);
+    // both services are implemented by the same struct
+    let circuits_api = Arc::new(circuits_api);
+    // gRPC message compression; only used when the client advertises it
+    let circuits_ext_api = InterceptedService::new(
+        circuits_ext_routes::SkcdExtApiServer::from_arc(circuits_api.clone())
+            .send_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Gzip),
+        auth_interceptor.clone(),
+    );
+    let circuits_api = InterceptedService::new(
+        circuits_routes::SkcdApiServer::from_arc(circuits_api)
+            .send_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Gzip),
+        auth_interceptor,
+    );
+
+    if let Some(metrics_addr) = args.metrics_bind_addr_port {
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr).await {
+                log::error!("metrics server failed: {err}");
+            }
+        });
+    }
 
-    Server::builder()
+    let addr: SocketAddr = args.bind_addr_port.parse()?;
+
+    let router = Server::builder()
         .accept_http1(true)
         .layer(GrpcWebLayer::new())
         .add_service(circuits_api)
-        .serve(addr)
-        .await?;
+        .add_service(circuits_ext_api);
+
+    match (args.tls_cert_path, args.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let tls_config = tls::ReloadableTlsConfig::new(tls::TlsPaths {
+                cert_path,
+                key_path,
+                client_ca_path: args.tls_client_ca_path,
+            })?;
+
+            let tls_config_watched = tls_config.clone();
+            file_watch::spawn_watcher(
+                tls_config.paths().all(),
+                Duration::from_secs(args.tls_reload_interval_secs),
+                move || {
+                    if let Err(err) = tls_config_watched.reload() {
+                        log::error!("tls: reload failed, keeping the previous config: {err}");
+                    }
+                },
+            );
+
+            let listener = TcpListener::bind(addr).await?;
+            println!("Server listening on {addr} (TLS)");
+
+            router
+                .serve_with_incoming(tls::incoming(listener, tls_config))
+                .await?;
+        }
+        _ => {
+            println!("Server listening on {addr}");
+
+            router.serve(addr).await?;
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,302 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Provenance manifests: for each generated circuit, what it was generated
+// from and with which toolchain, signed with the server's Ed25519 key.
+//
+// The signature is over the exact JSON bytes(`SignedManifest::manifest_json`);
+// they are NEVER re-serialized, so the verifiers do not depend on a canonical
+// JSON form.
+
+use ed25519_dalek::pkcs8::DecodePrivateKey;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::LazyLock;
+
+use crate::compression::ContentEncoding;
+use crate::pinning::CircuitKind;
+use crate::port_map::PortMapping;
+use crate::skcd::SkcdStats;
+
+/// Bumped on incompatible changes of `Manifest`
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Computed once: they are fixed at build time
+static TOOLCHAIN: LazyLock<Toolchain> = LazyLock::new(|| {
+    let versions = lib_circuits_wrapper::ffi::GetToolchainVersions();
+    Toolchain {
+        api_circuits: env!("CARGO_PKG_VERSION").to_string(),
+        lib_circuits: versions.lib_circuits,
+        yosys: versions.yosys,
+        abc: versions.abc,
+    }
+});
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ManifestInputs {
+    Display {
+        width: u32,
+        height: u32,
+        digits_bboxes: Vec<f32>,
+    },
+    Generic {
+        verilog_cid: String,
+        /// the interface of the Verilog; empty if it could NOT be computed
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        port_map: Vec<PortMapping>,
+    },
+}
+
+impl ManifestInputs {
+    #[must_use]
+    pub fn kind(&self) -> CircuitKind {
+        match self {
+            ManifestInputs::Display { .. } => CircuitKind::Display,
+            ManifestInputs::Generic { .. } => CircuitKind::Generic,
+        }
+    }
+}
+
+/// How the skcd was generated, cf `GenerateOptions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct ManifestGeneration {
+    /// if true: the same inputs and toolchain give the same `skcd_cid`
+    pub deterministic: bool,
+}
+
+impl From<lib_circuits_wrapper::ffi::GenerationOptions> for ManifestGeneration {
+    fn from(options: lib_circuits_wrapper::ffi::GenerationOptions) -> Self {
+        Self {
+            deterministic: options.deterministic,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Toolchain {
+    pub api_circuits: String,
+    pub lib_circuits: String,
+    pub yosys: String,
+    pub abc: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestStats {
+    pub nb_inputs: u32,
+    pub nb_outputs: u32,
+    pub nb_gates: u64,
+    /// key: eg "Xor"
+    pub gates_by_type: BTreeMap<String, u64>,
+}
+
+impl From<&SkcdStats> for ManifestStats {
+    fn from(stats: &SkcdStats) -> Self {
+        Self {
+            nb_inputs: stats.nb_inputs,
+            nb_outputs: stats.nb_outputs,
+            nb_gates: stats.nb_gates,
+            gates_by_type: stats
+                .gates_by_type
+                .iter()
+                .map(|(gate_type, count)| (format!("{gate_type:?}"), *count))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub inputs: ManifestInputs,
+    /// default: manifests from before it was added were NOT deterministic
+    #[serde(default)]
+    pub generation: ManifestGeneration,
+    pub toolchain: Toolchain,
+    /// seconds since `UNIX_EPOCH`
+    pub created_at: u64,
+    pub skcd_cid: String,
+    pub content_encoding: ContentEncoding,
+    pub stats: ManifestStats,
+}
+
+impl Manifest {
+    #[must_use]
+    pub fn new(
+        inputs: ManifestInputs,
+        generation: ManifestGeneration,
+        skcd_cid: String,
+        content_encoding: ContentEncoding,
+        stats: &SkcdStats,
+    ) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            inputs,
+            generation,
+            toolchain: TOOLCHAIN.clone(),
+            created_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs()),
+            skcd_cid,
+            content_encoding,
+            stats: stats.into(),
+        }
+    }
+}
+
+/// What is stored in IPFS and returned to the clients.
+/// Hex for the binary fields b/c this is also stored as JSON.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedManifest {
+    /// `Manifest` as JSON
+    pub manifest_json: String,
+    /// hex: Ed25519 signature of `manifest_json`
+    pub signature: String,
+    /// hex: Ed25519 public key of the server that signed it
+    pub public_key: String,
+}
+
+pub struct ManifestSigner {
+    signing_key: SigningKey,
+}
+
+impl ManifestSigner {
+    /// PKCS#8 PEM eg `openssl genpkey -algorithm ed25519`
+    ///
+    /// # Errors
+    /// if the file can not be read or is not an Ed25519 private key
+    pub fn from_pem_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let pem = std::fs::read_to_string(path)?;
+        Ok(Self {
+            signing_key: SigningKey::from_pkcs8_pem(&pem)
+                .map_err(|err| format!("{}: {err}", path.display()))?,
+        })
+    }
+
+    /// A random key; the manifests can then only be verified while this
+    /// process runs, or against the public key in the logs
+    #[must_use]
+    pub fn ephemeral() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut rand::rngs::OsRng),
+        }
+    }
+
+    #[must_use]
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// # Errors
+    /// if `manifest` can not be serialized
+    pub fn sign(&self, manifest: &Manifest) -> Result<SignedManifest, serde_json::Error> {
+        let manifest_json = serde_json::to_string(manifest)?;
+        let signature = self.signing_key.sign(manifest_json.as_bytes());
+
+        Ok(SignedManifest {
+            manifest_json,
+            signature: hex::encode(signature.to_bytes()),
+            public_key: self.public_key_hex(),
+        })
+    }
+
+    /// Check that `signed_manifest` was signed by THIS server's key.
+    ///
+    /// # Errors
+    /// the reason why it is NOT valid
+    pub fn verify(&self, signed_manifest: &SignedManifest) -> Result<Manifest, String> {
+        if signed_manifest.public_key != self.public_key_hex() {
+            return Err(format!(
+                "signed by {}, NOT by this server({})",
+                signed_manifest.public_key,
+                self.public_key_hex()
+            ));
+        }
+
+        let signature_bytes: [u8; 64] = hex::decode(&signed_manifest.signature)
+            .map_err(|err| format!("invalid signature: {err}"))?
+            .try_into()
+            .map_err(|_| "invalid signature: wrong length".to_string())?;
+        let verifying_key: VerifyingKey = self.signing_key.verifying_key();
+        verifying_key
+            .verify(
+                signed_manifest.manifest_json.as_bytes(),
+                &Signature::from_bytes(&signature_bytes),
+            )
+            .map_err(|err| format!("invalid signature: {err}"))?;
+
+        serde_json::from_str(&signed_manifest.manifest_json)
+            .map_err(|err| format!("invalid manifest: {err}"))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn signed_manifest(signer: &ManifestSigner) -> SignedManifest {
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            inputs: ManifestInputs::Generic {
+                verilog_cid: "QmVerilog".to_string(),
+                port_map: vec![],
+            },
+            generation: ManifestGeneration::default(),
+            toolchain: Toolchain {
+                api_circuits: "0.1.0".to_string(),
+                lib_circuits: "v1".to_string(),
+                yosys: "v2".to_string(),
+                abc: "v3".to_string(),
+            },
+            created_at: 42,
+            skcd_cid: "QmSkcd".to_string(),
+            content_encoding: ContentEncoding::Identity,
+            stats: ManifestStats {
+                nb_inputs: 2,
+                nb_outputs: 1,
+                nb_gates: 1,
+                gates_by_type: [("Xor".to_string(), 1)].into_iter().collect(),
+            },
+        };
+
+        signer.sign(&manifest).unwrap()
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let signer = ManifestSigner::ephemeral();
+        let signed = signed_manifest(&signer);
+
+        assert_eq!(signer.verify(&signed).unwrap().skcd_cid, "QmSkcd");
+    }
+
+    #[test]
+    fn test_verify_tampered() {
+        let signer = ManifestSigner::ephemeral();
+        let mut signed = signed_manifest(&signer);
+        signed.manifest_json = signed.manifest_json.replace("QmSkcd", "QmOther");
+
+        assert!(signer.verify(&signed).is_err());
+    }
+
+    #[test]
+    fn test_verify_other_server() {
+        let signed = signed_manifest(&ManifestSigner::ephemeral());
+
+        assert!(ManifestSigner::ephemeral().verify(&signed).is_err());
+    }
+}
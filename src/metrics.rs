@@ -0,0 +1,107 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Prometheus metrics, exposed as text on "GET /metrics" cf `serve`.
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Response, StatusCode};
+use prometheus::{
+    register_counter_vec, register_int_counter_vec, CounterVec, Encoder, IntCounterVec, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+
+/// labels: rpc, caller cf `Caller::metrics_label`
+#[allow(clippy::expect_used)]
+pub static REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "api_circuits_requests_total",
+        "Number of requests accepted by the generation RPCs",
+        &["rpc", "caller"]
+    )
+    .expect("register api_circuits_requests_total")
+});
+
+/// labels: rpc, caller cf `Caller::metrics_label`
+#[allow(clippy::expect_used)]
+pub static REQUESTS_DENIED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "api_circuits_requests_denied_total",
+        "Number of requests rejected b/c the caller lacks the permission",
+        &["rpc", "caller"]
+    )
+    .expect("register api_circuits_requests_denied_total")
+});
+
+/// labels: rpc
+#[allow(clippy::expect_used)]
+pub static GENERATIONS_CANCELLED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "api_circuits_generations_cancelled_total",
+        "Number of generations abandoned b/c the client went away or the deadline was exceeded",
+        &["rpc"]
+    )
+    .expect("register api_circuits_generations_cancelled_total")
+});
+
+/// labels: rpc
+#[allow(clippy::expect_used)]
+pub static GENERATIONS_WASTED_SECONDS_TOTAL: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "api_circuits_generations_wasted_seconds_total",
+        "Time spent on generations whose result was discarded",
+        &["rpc"]
+    )
+    .expect("register api_circuits_generations_wasted_seconds_total")
+});
+
+/// Serve the registry on `addr`; this is a separate listener from the gRPC one
+/// so that it is NOT exposed publicly by mistake.
+///
+/// # Errors
+/// if `addr` can not be bound
+pub async fn serve(addr: SocketAddr) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req| async move {
+            let response = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+                render()
+            } else {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+            };
+
+            response.or_else(|err| {
+                log::error!("metrics: {err}");
+                Ok::<_, Infallible>(Response::new(Body::empty()))
+            })
+        }))
+    });
+
+    log::info!("metrics listening on {addr}");
+    hyper::Server::bind(&addr).serve(make_service).await
+}
+
+fn render() -> Result<Response<Body>, hyper::http::Error> {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    if let Err(err) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::error!("metrics: encode failed: {err}");
+    }
+
+    Response::builder()
+        .header(hyper::header::CONTENT_TYPE, encoder.format_type())
+        .body(Body::from(buffer))
+}
@@ -0,0 +1,731 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Lifecycle of the circuits we add to IPFS:
+// - every skcd is pinned locally(cf `IpfsStorage::add`) and optionally on a
+//   remote pinning service(https://ipfs.github.io/pinning-services-api-spec/)
+// - they are recorded in a registry(JSON file) so that we know which CIDs
+//   were created by this service
+// - they are unpinned after a TTL, or on demand cf `SkcdExtApi::UnpinCircuit`
+
+use hyper::client::HttpConnector;
+use hyper::{Body, Method, StatusCode};
+use hyper_rustls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+use tonic::Status;
+
+use crate::compression::ContentEncoding;
+use crate::ipfs::IpfsStorage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitKind {
+    Display,
+    Generic,
+}
+
+impl CircuitKind {
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitKind::Display => "display",
+            CircuitKind::Generic => "generic",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinnedCircuit {
+    pub skcd_cid: String,
+    pub kind: CircuitKind,
+    /// seconds since `UNIX_EPOCH`
+    pub created_at: u64,
+    pub caller: String,
+    /// default: registries written before compression was supported
+    #[serde(default)]
+    pub content_encoding: ContentEncoding,
+    /// cf `manifest::SignedManifest`; None: registries written before manifests
+    #[serde(default)]
+    pub manifest_cid: Option<String>,
+    /// when the same skcd was registered again eg deterministic generation:
+    /// each registration has its own manifest; unpinned along with the skcd
+    #[serde(default)]
+    pub superseded_manifest_cids: Vec<String>,
+    /// "requestid" returned by the remote pinning service; None if not remote pinned
+    pub remote_request_id: Option<String>,
+    /// cf `SkcdExtApi::GarbleSkcd`; unpinned along with the skcd
+    /// NOTE: NOT remote pinned: they are cheap to re-create
+    #[serde(default)]
+    pub garbled_cids: Vec<String>,
+    /// incremented by each `register`; `unpin` does NOT remove an entry
+    /// registered again in the meantime
+    #[serde(default)]
+    pub registrations: u64,
+}
+
+/// Minimal client for the IPFS Pinning Service API
+/// cf <https://ipfs.github.io/pinning-services-api-spec/>
+pub struct RemotePinningService {
+    /// eg `https://api.pinata.cloud/psa`; "/pins" is appended
+    endpoint: String,
+    access_token: String,
+    client: hyper::Client<HttpsConnector<HttpConnector>>,
+}
+
+#[derive(Serialize)]
+struct PinRequest<'a> {
+    cid: &'a str,
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct PinStatusResponse {
+    requestid: String,
+}
+
+impl RemotePinningService {
+    /// `allow_http`: the access token is then sent in clear; only for tests
+    /// or a service on localhost
+    ///
+    /// # Errors
+    /// if `endpoint` is NOT https and `allow_http` is false
+    pub fn new(endpoint: String, access_token: String, allow_http: bool) -> Result<Self, String> {
+        if !allow_http && !endpoint.starts_with("https://") {
+            return Err(format!(
+                "remote pinning: {endpoint} is NOT https; the access token would be sent in clear"
+            ));
+        }
+        let https = hyper_rustls::HttpsConnectorBuilder::new().with_native_roots();
+        let https = if allow_http {
+            https.https_or_http()
+        } else {
+            https.https_only()
+        };
+
+        Ok(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            access_token,
+            client: hyper::Client::builder().build(https.enable_http1().build()),
+        })
+    }
+
+    /// "POST /pins"; return the "requestid" to use with `unpin`
+    ///
+    /// # Errors
+    /// if the service can not be reached or does not accept the request
+    pub async fn pin(&self, cid: &str, name: &str) -> Result<String, Status> {
+        let body = serde_json::to_vec(&PinRequest { cid, name })
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let response_body = self
+            .request(Method::POST, format!("{}/pins", self.endpoint), body)
+            .await
+            .map_err(Status::from)?;
+
+        let pin_status: PinStatusResponse = serde_json::from_slice(&response_body)
+            .map_err(|err| Status::unavailable(format!("remote pinning: {err}")))?;
+        Ok(pin_status.requestid)
+    }
+
+    /// "DELETE /pins/{requestid}"; NOT found is a success ie already unpinned
+    ///
+    /// # Errors
+    /// if the service can not be reached or does not accept the request
+    pub async fn unpin(&self, request_id: &str) -> Result<(), Status> {
+        match self
+            .request(
+                Method::DELETE,
+                format!("{}/pins/{request_id}", self.endpoint),
+                vec![],
+            )
+            .await
+        {
+            Ok(_) | Err(RequestError::Status(StatusCode::NOT_FOUND, _)) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn request(
+        &self,
+        method: Method,
+        uri: String,
+        body: Vec<u8>,
+    ) -> Result<hyper::body::Bytes, RequestError> {
+        let request = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                hyper::header::AUTHORIZATION,
+                format!("Bearer {}", self.access_token),
+            )
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|err| Status::unavailable(format!("remote pinning: {err}")))?;
+        let status = response.status();
+        let response_body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|err| Status::unavailable(format!("remote pinning: {err}")))?;
+
+        // the spec returns 202 for both POST and DELETE; but be lenient
+        if !status.is_success() && status != StatusCode::ACCEPTED {
+            return Err(RequestError::Status(
+                status,
+                String::from_utf8_lossy(&response_body).to_string(),
+            ));
+        }
+
+        Ok(response_body)
+    }
+}
+
+enum RequestError {
+    Failed(Status),
+    /// the service replied with an error status; and its body
+    Status(StatusCode, String),
+}
+
+impl From<Status> for RequestError {
+    fn from(status: Status) -> Self {
+        RequestError::Failed(status)
+    }
+}
+
+impl From<RequestError> for Status {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::Failed(status) => status,
+            RequestError::Status(status, body) => {
+                Status::unavailable(format!("remote pinning: {status}: {body}"))
+            }
+        }
+    }
+}
+
+pub struct PinManager {
+    ipfs: Arc<IpfsStorage>,
+    remote: Option<RemotePinningService>,
+    /// None: the registry is in memory only ie lost on restart
+    registry_path: Option<PathBuf>,
+    /// key: `skcd_cid`
+    /// NOTE: std Mutex b/c it is NEVER held across an await cf `update`
+    circuits: Mutex<Registry>,
+    /// serialize the writes of the registry file; the version last written
+    written_version: tokio::sync::Mutex<u64>,
+}
+
+struct Registry {
+    circuits: BTreeMap<String, PinnedCircuit>,
+    /// incremented by each `update`
+    version: u64,
+}
+
+impl PinManager {
+    /// No remote pinning, and the registry is NOT persisted
+    #[must_use]
+    pub fn in_memory(ipfs: Arc<IpfsStorage>) -> Self {
+        Self {
+            ipfs,
+            remote: None,
+            registry_path: None,
+            circuits: Mutex::new(Registry {
+                circuits: BTreeMap::new(),
+                version: 0,
+            }),
+            written_version: tokio::sync::Mutex::new(0),
+        }
+    }
+
+    /// # Errors
+    /// if `registry_path` exists but can not be read or parsed
+    pub fn new(
+        ipfs: Arc<IpfsStorage>,
+        remote: Option<RemotePinningService>,
+        registry_path: Option<PathBuf>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let circuits = match &registry_path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => BTreeMap::new(),
+        };
+
+        Ok(Self {
+            ipfs,
+            remote,
+            registry_path,
+            circuits: Mutex::new(Registry {
+                circuits,
+                version: 0,
+            }),
+            written_version: tokio::sync::Mutex::new(0),
+        })
+    }
+
+    /// MUST be called for each skcd added to IPFS.
+    /// If it is already registered(eg deterministic generation) the entry is
+    /// refreshed ie its TTL restarts; what was pinned for it is kept.
+    /// Failures(remote pinning service, registry) are logged but NOT returned:
+    /// the circuit is pinned locally anyway so the request can still succeed.
+    pub async fn register(
+        &self,
+        skcd_cid: &str,
+        kind: CircuitKind,
+        content_encoding: ContentEncoding,
+        manifest_cid: &str,
+        caller: &str,
+    ) {
+        let already_remote_pinned = self
+            .get(skcd_cid)
+            .is_ok_and(|circuit| circuit.remote_request_id.is_some());
+        let remote_request_id = match &self.remote {
+            Some(remote) if !already_remote_pinned => remote
+                .pin(skcd_cid, &format!("api_circuits-{}", kind.as_str()))
+                .await
+                .inspect_err(|err| log::warn!("pinning: remote pin of {skcd_cid} failed: {err}"))
+                .ok(),
+            _ => None,
+        };
+
+        let result = self
+            .update(|circuits| {
+                let circuit =
+                    circuits
+                        .entry(skcd_cid.to_string())
+                        .or_insert_with(|| PinnedCircuit {
+                            skcd_cid: skcd_cid.to_string(),
+                            kind,
+                            created_at: 0,
+                            caller: caller.to_string(),
+                            content_encoding,
+                            manifest_cid: None,
+                            superseded_manifest_cids: vec![],
+                            remote_request_id: None,
+                            garbled_cids: vec![],
+                            registrations: 0,
+                        });
+                circuit.registrations += 1;
+                circuit.created_at = now_secs();
+                circuit.caller = caller.to_string();
+                circuit.content_encoding = content_encoding;
+                if let Some(previous) = circuit.manifest_cid.replace(manifest_cid.to_string()) {
+                    if previous != manifest_cid
+                        && !circuit.superseded_manifest_cids.contains(&previous)
+                    {
+                        circuit.superseded_manifest_cids.push(previous);
+                    }
+                }
+                if remote_request_id.is_some() {
+                    circuit.remote_request_id = remote_request_id;
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            log::error!("pinning: could not register {skcd_cid}: {err}");
+        }
+    }
+
+    /// MUST be called for each garbled circuit added to IPFS.
+    /// Failures are logged but NOT returned cf `register`
+    pub async fn register_garbled(&self, skcd_cid: &str, garbled_cid: &str) {
+        let result = self
+            .update(|circuits| {
+                if let Some(circuit) = circuits.get_mut(skcd_cid) {
+                    // eg the same seed twice
+                    if !circuit.garbled_cids.iter().any(|cid| cid == garbled_cid) {
+                        circuit.garbled_cids.push(garbled_cid.to_string());
+                    }
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            log::error!("pinning: could not register {garbled_cid}(garbled {skcd_cid}): {err}");
+        }
+    }
+
+    /// # Errors
+    /// if the registry can not be read
+    pub fn list(&self) -> Result<Vec<PinnedCircuit>, Status> {
+        Ok(self
+            .circuits
+            .lock()
+            .map_err(|err| Status::internal(err.to_string()))?
+            .circuits
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    /// # Errors
+    /// `not_found` if `skcd_cid` was not created by this service
+    pub fn get(&self, skcd_cid: &str) -> Result<PinnedCircuit, Status> {
+        self.circuits
+            .lock()
+            .map_err(|err| Status::internal(err.to_string()))?
+            .circuits
+            .get(skcd_cid)
+            .cloned()
+            .ok_or_else(|| Status::not_found(format!("{skcd_cid} was not created by us")))
+    }
+
+    /// Unpin locally and remotely, and remove from the registry.
+    /// Each CID is removed from the entry as soon as it is unpinned; so after a
+    /// failure, a retry(eg the GC) only does what is left.
+    ///
+    /// # Errors
+    /// - `not_found` if `skcd_cid` was not created by this service
+    /// - `unavailable` if IPFS or the remote pinning service fail
+    pub async fn unpin(&self, skcd_cid: &str) -> Result<(), Status> {
+        let circuit = self.get(skcd_cid)?;
+
+        for garbled_cid in &circuit.garbled_cids {
+            self.ipfs.pin_rm(garbled_cid).await?;
+            self.update_circuit(skcd_cid, |circuit| {
+                circuit.garbled_cids.retain(|cid| cid != garbled_cid);
+            })
+            .await?;
+        }
+        for manifest_cid in &circuit.superseded_manifest_cids {
+            self.ipfs.pin_rm(manifest_cid).await?;
+            self.update_circuit(skcd_cid, |circuit| {
+                circuit
+                    .superseded_manifest_cids
+                    .retain(|cid| cid != manifest_cid);
+            })
+            .await?;
+        }
+        if let Some(manifest_cid) = &circuit.manifest_cid {
+            self.ipfs.pin_rm(manifest_cid).await?;
+            self.update_circuit(skcd_cid, |circuit| circuit.manifest_cid = None)
+                .await?;
+        }
+        if let (Some(remote), Some(request_id)) = (&self.remote, &circuit.remote_request_id) {
+            remote.unpin(request_id).await?;
+            self.update_circuit(skcd_cid, |circuit| circuit.remote_request_id = None)
+                .await?;
+        }
+
+        // last: it is the registry key
+        // NOT if it was registered again(eg the same deterministic generation)
+        // since we read it: its new registration expects it to stay pinned
+        if self.get(skcd_cid)?.registrations != circuit.registrations {
+            log::info!("pinning: {skcd_cid} was registered again, NOT unpinned");
+            return Ok(());
+        }
+        self.ipfs.pin_rm(skcd_cid).await?;
+        if !self
+            .remove_if_unchanged(skcd_cid, circuit.registrations)
+            .await?
+        {
+            // registered again during `pin_rm`: kept in the registry, the TTL
+            // will retry; until then it is NOT pinned locally
+            log::warn!("pinning: {skcd_cid} was registered again while being unpinned");
+            return Ok(());
+        }
+        log::info!("pinning: unpinned {skcd_cid}");
+        Ok(())
+    }
+
+    /// Remove `skcd_cid` from the registry iff it has NOT been registered
+    /// again since `registrations` was read.
+    /// Return whether it was removed.
+    async fn remove_if_unchanged(
+        &self,
+        skcd_cid: &str,
+        registrations: u64,
+    ) -> Result<bool, Status> {
+        let mut removed = false;
+        self.update(|circuits| {
+            if circuits
+                .get(skcd_cid)
+                .is_some_and(|circuit| circuit.registrations == registrations)
+            {
+                circuits.remove(skcd_cid);
+                removed = true;
+            }
+        })
+        .await?;
+
+        Ok(removed)
+    }
+
+    /// Periodically unpin the circuits older than `ttl`
+    pub fn spawn_ttl_gc(self: Arc<Self>, ttl: Duration, interval: Duration) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+
+                let expired: Vec<String> = match self.list() {
+                    Ok(circuits) => circuits
+                        .into_iter()
+                        .filter(|circuit| circuit.created_at + ttl.as_secs() < now_secs())
+                        .map(|circuit| circuit.skcd_cid)
+                        .collect(),
+                    Err(err) => {
+                        log::error!("pinning: gc failed: {err}");
+                        continue;
+                    }
+                };
+
+                for skcd_cid in expired {
+                    if let Err(err) = self.unpin(&skcd_cid).await {
+                        log::warn!("pinning: gc could not unpin {skcd_cid}: {err}");
+                    }
+                }
+            }
+        })
+    }
+
+    async fn update_circuit<F>(&self, skcd_cid: &str, f: F) -> Result<(), Status>
+    where
+        F: FnOnce(&mut PinnedCircuit),
+    {
+        self.update(|circuits| {
+            if let Some(circuit) = circuits.get_mut(skcd_cid) {
+                f(circuit);
+            }
+        })
+        .await
+    }
+
+    /// Apply `f` to the registry, and persist it.
+    /// The file is written outside of the lock and in a blocking thread.
+    async fn update<F>(&self, f: F) -> Result<(), Status>
+    where
+        F: FnOnce(&mut BTreeMap<String, PinnedCircuit>),
+    {
+        let snapshot = {
+            let mut registry = self
+                .circuits
+                .lock()
+                .map_err(|err| Status::internal(err.to_string()))?;
+            f(&mut registry.circuits);
+            registry.version += 1;
+
+            match &self.registry_path {
+                Some(registry_path) => Some((
+                    registry_path.clone(),
+                    serde_json::to_vec_pretty(&registry.circuits)
+                        .map_err(|err| Status::internal(err.to_string()))?,
+                    registry.version,
+                )),
+                None => None,
+            }
+        };
+        let Some((registry_path, json, version)) = snapshot else {
+            return Ok(());
+        };
+
+        // concurrent updates: do NOT overwrite a newer registry with an older one
+        let mut written_version = self.written_version.lock().await;
+        if *written_version > version {
+            return Ok(());
+        }
+        tokio::task::spawn_blocking(move || write_atomic(&registry_path, &json))
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_err(|err| Status::internal(format!("pinning: registry: {err}")))?;
+        *written_version = version;
+
+        Ok(())
+    }
+}
+
+/// Write to a temporary file then rename, so that a crash does NOT leave a
+/// truncated registry.
+fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| Path::new("."));
+    let mut tmp_file = tempfile::NamedTempFile::new_in(dir)?;
+    tmp_file.write_all(content)?;
+    tmp_file.as_file().sync_all()?;
+    tmp_file.persist(path).map_err(|err| err.error)?;
+
+    Ok(())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    /// local stand-in for a Pinning Service: records "METHOD path body"
+    async fn spawn_pinning_service() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+
+        let requests_server = requests.clone();
+        let make_service = make_service_fn(move |_| {
+            let requests = requests_server.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: hyper::Request<Body>| {
+                    let requests = requests.clone();
+                    async move {
+                        assert_eq!(
+                            request.headers()[hyper::header::AUTHORIZATION],
+                            "Bearer secret"
+                        );
+                        let method = request.method().clone();
+                        let path = request.uri().path().to_string();
+                        let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+                        requests.lock().unwrap().push(format!(
+                            "{method} {path} {}",
+                            String::from_utf8_lossy(&body)
+                        ));
+
+                        Ok::<_, Infallible>(
+                            hyper::Response::builder()
+                                .status(StatusCode::ACCEPTED)
+                                .body(Body::from(r#"{"requestid":"req-1","status":"queued"}"#))
+                                .unwrap(),
+                        )
+                    }
+                }))
+            }
+        });
+
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_register_again_keeps_garbled() {
+        let registry_dir = tempfile::tempdir().unwrap();
+        let registry_path = registry_dir.path().join("registry.json");
+        let ipfs = IpfsStorage::new(crate::ipfs::IpfsConfig::new(vec![
+            "/ip4/127.0.0.1/tcp/5001".to_string(),
+        ]))
+        .unwrap();
+        let pins = PinManager::new(Arc::new(ipfs), None, Some(registry_path.clone())).unwrap();
+
+        let register = |manifest_cid| {
+            pins.register(
+                "QmSkcd",
+                CircuitKind::Display,
+                ContentEncoding::Identity,
+                manifest_cid,
+                "test",
+            )
+        };
+        register("QmManifest1").await;
+        pins.register_garbled("QmSkcd", "QmGarbled").await;
+        register("QmManifest2").await;
+
+        let circuit = pins.get("QmSkcd").unwrap();
+        assert_eq!(circuit.garbled_cids, vec!["QmGarbled".to_string()]);
+        assert_eq!(circuit.manifest_cid.as_deref(), Some("QmManifest2"));
+        assert_eq!(
+            circuit.superseded_manifest_cids,
+            vec!["QmManifest1".to_string()]
+        );
+
+        // persisted
+        let persisted: BTreeMap<String, PinnedCircuit> =
+            serde_json::from_slice(&std::fs::read(&registry_path).unwrap()).unwrap();
+        assert_eq!(persisted["QmSkcd"], circuit);
+    }
+
+    #[tokio::test]
+    async fn test_remove_if_unchanged() {
+        let ipfs = IpfsStorage::new(crate::ipfs::IpfsConfig::new(vec![
+            "/ip4/127.0.0.1/tcp/5001".to_string(),
+        ]))
+        .unwrap();
+        let pins = PinManager::in_memory(Arc::new(ipfs));
+        let register = || {
+            pins.register(
+                "QmSkcd",
+                CircuitKind::Display,
+                ContentEncoding::Identity,
+                "QmManifest",
+                "test",
+            )
+        };
+        register().await;
+        let read = pins.get("QmSkcd").unwrap();
+
+        // eg the same deterministic generation during `unpin`
+        register().await;
+        assert!(!pins
+            .remove_if_unchanged("QmSkcd", read.registrations)
+            .await
+            .unwrap());
+        assert!(pins.get("QmSkcd").is_ok());
+
+        let read = pins.get("QmSkcd").unwrap();
+        assert!(pins
+            .remove_if_unchanged("QmSkcd", read.registrations)
+            .await
+            .unwrap());
+        assert!(pins.get("QmSkcd").is_err());
+    }
+
+    #[test]
+    fn test_remote_requires_https() {
+        assert!(RemotePinningService::new(
+            "http://pins.example.com".to_string(),
+            "secret".to_string(),
+            false
+        )
+        .is_err());
+        assert!(RemotePinningService::new(
+            "https://pins.example.com".to_string(),
+            "secret".to_string(),
+            false
+        )
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_remote_pin_unpin() {
+        let (addr, requests) = spawn_pinning_service().await;
+        let remote =
+            RemotePinningService::new(format!("http://{addr}/"), "secret".to_string(), true)
+                .unwrap();
+
+        let request_id = remote.pin("QmAAA", "api_circuits-display").await.unwrap();
+        assert_eq!(request_id, "req-1");
+        remote.unpin(&request_id).await.unwrap();
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                r#"POST /pins {"cid":"QmAAA","name":"api_circuits-display"}"#.to_string(),
+                "DELETE /pins/req-1 ".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,100 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Which skcd inputs/outputs are which Verilog port, for the generic circuits.
+// yosys writes the BLIF ports in declaration order(LSB first) and the skcd
+// keeps the order of the BLIF, so the bits of a port are contiguous.
+// That is checked against the number of inputs/outputs of the skcd.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortDirection {
+    Input,
+    Output,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortMapping {
+    pub name: String,
+    pub direction: PortDirection,
+    /// in bits
+    pub width: u32,
+    /// LSB first: the indices in the skcd inputs(or outputs) of each bit
+    pub skcd_bits: Vec<u32>,
+}
+
+/// `ports`: (name, direction, width) in declaration order
+///
+/// # Errors
+/// if the ports do NOT add up to `nb_inputs`/`nb_outputs`
+pub fn compute_port_map(
+    ports: &[(String, PortDirection, u32)],
+    nb_inputs: u32,
+    nb_outputs: u32,
+) -> Result<Vec<PortMapping>, String> {
+    let (mut next_input, mut next_output) = (0u32, 0u32);
+    let port_map: Vec<_> = ports
+        .iter()
+        .map(|(name, direction, width)| {
+            let next = match direction {
+                PortDirection::Input => &mut next_input,
+                PortDirection::Output => &mut next_output,
+            };
+            let start = *next;
+            *next = next.saturating_add(*width);
+            PortMapping {
+                name: name.clone(),
+                direction: *direction,
+                width: *width,
+                skcd_bits: (start..*next).collect(),
+            }
+        })
+        .collect();
+
+    if (next_input, next_output) != (nb_inputs, nb_outputs) {
+        return Err(format!(
+            "ports have {next_input} input and {next_output} output bits, skcd has {nb_inputs} and {nb_outputs}"
+        ));
+    }
+    Ok(port_map)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_port_map() {
+        let ports = [
+            ("a".to_string(), PortDirection::Input, 2),
+            ("sum".to_string(), PortDirection::Output, 2),
+            ("b".to_string(), PortDirection::Input, 2),
+            ("cout".to_string(), PortDirection::Output, 1),
+        ];
+
+        let port_map = compute_port_map(&ports, 4, 3).unwrap();
+        assert_eq!(
+            port_map
+                .iter()
+                .map(|port| port.skcd_bits.clone())
+                .collect::<Vec<_>>(),
+            vec![vec![0, 1], vec![0, 1], vec![2, 3], vec![2]]
+        );
+
+        assert!(compute_port_map(&ports, 5, 3).is_err());
+    }
+}
@@ -0,0 +1,385 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Per-client rate limiting(token bucket) and daily CPU quotas.
+//
+// A "display" generation is ~50s of CPU so without that a single client can
+// starve everyone else.
+// Clients are identified by their `auth::Caller` id when authenticated, else
+// by their IP.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tonic::metadata::MetadataValue;
+use tonic::Status;
+
+use crate::auth::{Caller, CallerKind};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// cf `evict_idle`
+const EVICTION_INTERVAL_SECS: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ClientLimits {
+    /// max number of requests accepted back-to-back
+    pub burst: u32,
+    /// rate at which the bucket is refilled
+    pub requests_per_minute: f64,
+    /// "wall time of the generation" per UTC day; the generation is single threaded
+    /// so this is basically CPU time
+    pub daily_cpu_seconds: u64,
+}
+
+impl Default for ClientLimits {
+    fn default() -> Self {
+        Self {
+            burst: 2,
+            requests_per_minute: 2.0,
+            daily_cpu_seconds: 3600,
+        }
+    }
+}
+
+/// cf `--rate-limit-config-path`, eg
+/// ```json
+/// {
+///   "default": { "burst": 2, "requests_per_minute": 2.0, "daily_cpu_seconds": 3600 },
+///   "overrides": {
+///     "mobile-backend": { "burst": 20, "requests_per_minute": 60.0, "daily_cpu_seconds": 86400 }
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub default: ClientLimits,
+    /// key: `Caller::id` or IP
+    #[serde(default)]
+    pub overrides: HashMap<String, ClientLimits>,
+}
+
+impl RateLimitConfig {
+    /// # Errors
+    /// if the file can not be read or is not a valid `RateLimitConfig`
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    fn limits_for(&self, client_key: &str) -> &ClientLimits {
+        self.overrides.get(client_key).unwrap_or(&self.default)
+    }
+}
+
+struct ClientState {
+    tokens: f64,
+    last_refill: Instant,
+    /// days since `UNIX_EPOCH`
+    quota_day: u64,
+    cpu_used: Duration,
+}
+
+impl ClientState {
+    /// ie it can be dropped and re-created by `check` without changing anything:
+    /// its bucket is full, and it used NO CPU today(else it would reset its quota)
+    fn is_idle(&self, limits: &ClientLimits, now: Instant, today: u64) -> bool {
+        let tokens = self.tokens
+            + now.duration_since(self.last_refill).as_secs_f64() * limits.requests_per_minute
+                / 60.0;
+        tokens >= f64::from(limits.burst) && (self.quota_day != today || self.cpu_used.is_zero())
+    }
+}
+
+struct Clients {
+    /// key: cf `client_key`
+    states: HashMap<String, ClientState>,
+    last_eviction: Instant,
+}
+
+pub struct RateLimiter {
+    config: RwLock<RateLimitConfig>,
+    clients: Mutex<Clients>,
+}
+
+/// The key used to track a client; the caller id if authenticated, else the IP
+/// NOT ip:port b/c the port changes with each connection.
+#[must_use]
+pub fn client_key(caller: &Caller, remote_addr: Option<SocketAddr>) -> String {
+    if caller.kind != CallerKind::Anonymous {
+        return caller.id.clone();
+    }
+
+    remote_addr.map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+impl RateLimiter {
+    #[must_use]
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            clients: Mutex::new(Clients {
+                states: HashMap::new(),
+                last_eviction: Instant::now(),
+            }),
+        }
+    }
+
+    /// Replace the limits; the current state(tokens, CPU used) of the clients is kept.
+    pub fn set_config(&self, config: RateLimitConfig) {
+        match self.config.write() {
+            Ok(mut current) => *current = config,
+            Err(err) => log::error!("rate_limit: set_config failed: {err}"),
+        }
+    }
+
+    /// MUST be called before starting a generation.
+    /// Consume a token.
+    ///
+    /// # Errors
+    /// `resource_exhausted` with a "retry-after"(seconds) in the metadata
+    pub fn check(&self, client_key: &str) -> Result<(), Status> {
+        let config = self
+            .config
+            .read()
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let limits = config.limits_for(client_key).clone();
+        let mut clients = self
+            .clients
+            .lock()
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        let now = Instant::now();
+        let today = current_day();
+        if now.duration_since(clients.last_eviction).as_secs() >= EVICTION_INTERVAL_SECS {
+            evict_idle(&mut clients, &config, now, today);
+        }
+        drop(config);
+
+        let state = clients
+            .states
+            .entry(client_key.to_string())
+            .or_insert_with(|| ClientState {
+                tokens: f64::from(limits.burst),
+                last_refill: now,
+                quota_day: today,
+                cpu_used: Duration::ZERO,
+            });
+
+        // refill the bucket
+        let refill_per_second = limits.requests_per_minute / 60.0;
+        state.tokens = (state.tokens
+            + now.duration_since(state.last_refill).as_secs_f64() * refill_per_second)
+            .min(f64::from(limits.burst));
+        state.last_refill = now;
+
+        // daily quota
+        if state.quota_day != today {
+            state.quota_day = today;
+            state.cpu_used = Duration::ZERO;
+        }
+        if state.cpu_used.as_secs() >= limits.daily_cpu_seconds {
+            return Err(resource_exhausted(
+                format!("{client_key}: daily CPU quota exhausted"),
+                seconds_until_next_day(),
+            ));
+        }
+
+        if state.tokens < 1.0 {
+            let retry_after = if refill_per_second > 0.0 {
+                seconds_until_refilled(state.tokens, refill_per_second)
+            } else {
+                seconds_until_next_day()
+            };
+            return Err(resource_exhausted(
+                format!("{client_key}: rate limit exceeded"),
+                retry_after,
+            ));
+        }
+        state.tokens -= 1.0;
+
+        Ok(())
+    }
+
+    /// MUST be called after a generation, successful or not: the CPU was used either way.
+    pub fn record_cpu_time(&self, client_key: &str, elapsed: Duration) {
+        let burst = match self.config.read() {
+            Ok(config) => config.limits_for(client_key).burst,
+            Err(err) => {
+                log::error!("rate_limit: record_cpu_time failed: {err}");
+                return;
+            }
+        };
+        match self.clients.lock() {
+            Ok(mut clients) => {
+                // it may have been evicted during the generation: its bucket was
+                // refilled in the meantime
+                let today = current_day();
+                let state = clients
+                    .states
+                    .entry(client_key.to_string())
+                    .or_insert_with(|| ClientState {
+                        tokens: f64::from(burst),
+                        last_refill: Instant::now(),
+                        quota_day: today,
+                        cpu_used: Duration::ZERO,
+                    });
+                if state.quota_day != today {
+                    state.quota_day = today;
+                    state.cpu_used = Duration::ZERO;
+                }
+                state.cpu_used += elapsed;
+            }
+            Err(err) => log::error!("rate_limit: record_cpu_time failed: {err}"),
+        }
+    }
+}
+
+/// Else `clients` grows with each new caller id/IP
+fn evict_idle(clients: &mut Clients, config: &RateLimitConfig, now: Instant, today: u64) {
+    let before = clients.states.len();
+    clients
+        .states
+        .retain(|client_key, state| !state.is_idle(config.limits_for(client_key), now, today));
+    clients.last_eviction = now;
+    log::debug!(
+        "rate_limit: evicted {} idle clients",
+        before - clients.states.len()
+    );
+}
+
+fn resource_exhausted(message: String, retry_after_secs: u64) -> Status {
+    log::warn!("rate_limit: {message}, retry after {retry_after_secs}s");
+    let mut status = Status::resource_exhausted(message);
+    status
+        .metadata_mut()
+        .insert("retry-after", MetadataValue::from(retry_after_secs));
+    status
+}
+
+// "tokens" is in [0, 1) here so the result is always positive
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn seconds_until_refilled(tokens: f64, refill_per_second: f64) -> u64 {
+    ((1.0 - tokens) / refill_per_second).ceil() as u64
+}
+
+fn now_since_epoch() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+}
+
+fn current_day() -> u64 {
+    now_since_epoch().as_secs() / SECONDS_PER_DAY
+}
+
+fn seconds_until_next_day() -> u64 {
+    SECONDS_PER_DAY - now_since_epoch().as_secs() % SECONDS_PER_DAY
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    fn limiter(limits: ClientLimits) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            default: limits,
+            overrides: HashMap::new(),
+        })
+    }
+
+    #[test]
+    fn token_bucket_burst() {
+        let rate_limiter = limiter(ClientLimits {
+            burst: 2,
+            requests_per_minute: 1.0,
+            daily_cpu_seconds: 3600,
+        });
+
+        assert!(rate_limiter.check("a").is_ok());
+        assert!(rate_limiter.check("a").is_ok());
+        let status = rate_limiter.check("a").unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+        assert!(status.metadata().get("retry-after").is_some());
+
+        // other clients are NOT impacted
+        assert!(rate_limiter.check("b").is_ok());
+    }
+
+    #[test]
+    fn daily_cpu_quota() {
+        let rate_limiter = limiter(ClientLimits {
+            burst: 10,
+            requests_per_minute: 60.0,
+            daily_cpu_seconds: 60,
+        });
+
+        assert!(rate_limiter.check("a").is_ok());
+        rate_limiter.record_cpu_time("a", Duration::from_secs(61));
+        assert_eq!(
+            rate_limiter.check("a").unwrap_err().code(),
+            tonic::Code::ResourceExhausted
+        );
+    }
+
+    #[test]
+    fn set_config_at_runtime() {
+        let rate_limiter = limiter(ClientLimits {
+            burst: 1,
+            requests_per_minute: 0.0,
+            daily_cpu_seconds: 3600,
+        });
+        assert!(rate_limiter.check("a").is_ok());
+        assert!(rate_limiter.check("a").is_err());
+
+        let mut config = RateLimitConfig::default();
+        config.overrides.insert(
+            "a".to_string(),
+            ClientLimits {
+                burst: 10,
+                requests_per_minute: 6000.0,
+                daily_cpu_seconds: 3600,
+            },
+        );
+        rate_limiter.set_config(config);
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(rate_limiter.check("a").is_ok());
+    }
+
+    #[test]
+    fn evict_idle_clients() {
+        let rate_limiter = limiter(ClientLimits {
+            burst: 1,
+            requests_per_minute: 6000.0,
+            daily_cpu_seconds: 3600,
+        });
+        assert!(rate_limiter.check("a").is_ok());
+        assert!(rate_limiter.check("b").is_ok());
+        rate_limiter.record_cpu_time("b", Duration::from_secs(1));
+
+        // both buckets are full again
+        std::thread::sleep(Duration::from_millis(50));
+        let mut clients = rate_limiter.clients.lock().unwrap();
+        let config = rate_limiter.config.read().unwrap();
+        evict_idle(&mut clients, &config, Instant::now(), current_day());
+
+        // "b" is kept else its CPU quota would be reset
+        assert!(!clients.states.contains_key("a"));
+        assert!(clients.states.contains_key("b"));
+    }
+}
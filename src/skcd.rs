@@ -0,0 +1,372 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Read-only view of the skcd.pb.bin returned by lib_circuits; used to report
+// stats about a circuit and to enforce limits(eg max gate count), and to
+// garble it cf `SkcdCircuit`.
+
+use std::collections::BTreeMap;
+use std::io::{BufReader, Error, ErrorKind, Read};
+use std::path::Path;
+
+// The subset of the "Skcd" message we need; the other fields are skipped.
+// MUST match `/lib_circuits/src/skcd/skcd.proto`
+// NOTE: parsed by hand instead of prost b/c prost needs the whole buffer, and
+// generic circuits can be far larger than we want in memory cf `from_skcd_reader`
+/// number of outputs
+const FIELD_M: u64 = 1;
+/// number of inputs
+const FIELD_N: u64 = 2;
+/// gate inputs; one per gate; packed or not
+const FIELD_A: u64 = 4;
+const FIELD_B: u64 = 5;
+/// gate output; one per gate; packed or not
+const FIELD_GO: u64 = 6;
+/// one per gate; packed or not
+const FIELD_GT: u64 = 7;
+/// output wires; packed or not
+const FIELD_O: u64 = 8;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// MUST match `/lib_circuits/src/skcd/skcd.proto`
+/// The value is the truth table of the gate: bit i <=> f(a, b) with i = a + 2*b
+/// eg "Buf"(0b1010) is a, "Invb"(0b0011) is NOT b
+/// cf `/lib_circuits/data/verilog/skcd.genlib`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum SkcdGateType {
+    Zero = 0,
+    Nor = 1,
+    Aanb = 2,
+    Invb = 3,
+    Naab = 4,
+    Inv = 5,
+    Xor = 6,
+    Nand = 7,
+    And = 8,
+    Xnor = 9,
+    Buf = 10,
+    Aonb = 11,
+    Bufb = 12,
+    Naob = 13,
+    Or = 14,
+    One = 15,
+}
+
+impl SkcdGateType {
+    #[must_use]
+    pub fn eval(self, a: bool, b: bool) -> bool {
+        (self as i32 >> (usize::from(a) + 2 * usize::from(b))) & 1 == 1
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkcdStats {
+    pub nb_inputs: u32,
+    pub nb_outputs: u32,
+    pub nb_gates: u64,
+    pub gates_by_type: BTreeMap<SkcdGateType, u64>,
+}
+
+impl SkcdStats {
+    /// # Errors
+    /// if `skcd_buf` is not a valid skcd.pb.bin
+    pub fn from_skcd_buf(skcd_buf: &[u8]) -> Result<Self, Error> {
+        Self::from_skcd_reader(skcd_buf)
+    }
+
+    /// Stream the file; it is never loaded in memory.
+    ///
+    /// # Errors
+    /// if the file can not be read or is not a valid skcd.pb.bin
+    pub fn from_skcd_file(path: &Path) -> Result<Self, Error> {
+        Self::from_skcd_reader(BufReader::new(std::fs::File::open(path)?))
+    }
+
+    /// # Errors
+    /// if `reader` fails or is not a valid skcd.pb.bin
+    pub fn from_skcd_reader<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut nb_inputs = 0;
+        let mut nb_outputs = 0;
+        let mut gates_by_type = BTreeMap::new();
+        let mut add_gate = |gate_type: u64| -> Result<(), Error> {
+            let gate_type = i32::try_from(gate_type)
+                .ok()
+                .and_then(SkcdGateType::from_i32)
+                .ok_or_else(|| invalid_data(&format!("invalid gate type: {gate_type}")))?;
+            *gates_by_type.entry(gate_type).or_insert(0) += 1;
+            Ok(())
+        };
+
+        while let Some(key) = read_varint(&mut reader)? {
+            match (key >> 3, key & 0x7) {
+                (FIELD_M, WIRE_VARINT) => nb_outputs = read_u32(&mut reader)?,
+                (FIELD_N, WIRE_VARINT) => nb_inputs = read_u32(&mut reader)?,
+                (FIELD_GT, WIRE_VARINT) => add_gate(read_required_varint(&mut reader)?)?,
+                (FIELD_GT, WIRE_LEN) => {
+                    let len = read_required_varint(&mut reader)?;
+                    let mut packed = (&mut reader).take(len);
+                    while let Some(gate_type) = read_varint(&mut packed)? {
+                        add_gate(gate_type)?;
+                    }
+                    if packed.limit() != 0 {
+                        return Err(invalid_data("truncated packed field"));
+                    }
+                }
+                (_, wire_type) => skip_field(&mut reader, wire_type)?,
+            }
+        }
+
+        Ok(Self {
+            nb_inputs,
+            nb_outputs,
+            nb_gates: gates_by_type.values().sum(),
+            gates_by_type,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SkcdGate {
+    pub a: u32,
+    pub b: u32,
+    /// output wire
+    pub o: u32,
+    pub gate_type: SkcdGateType,
+}
+
+/// The whole circuit, in memory; use `SkcdStats` when only the stats are needed.
+/// Wires `0..nb_inputs` are the inputs, the others are the outputs of the gates.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SkcdCircuit {
+    pub nb_inputs: u32,
+    /// in topological order ie a gate only uses the inputs or previous gates
+    pub gates: Vec<SkcdGate>,
+    /// output wires
+    pub outputs: Vec<u32>,
+}
+
+impl SkcdCircuit {
+    /// # Errors
+    /// if `skcd_buf` is not a valid skcd.pb.bin, or a wire is out of range
+    pub fn from_skcd_buf(mut skcd_buf: &[u8]) -> Result<Self, Error> {
+        let reader = &mut skcd_buf;
+        let mut nb_outputs = None;
+        let mut nb_inputs = 0;
+        let (mut a, mut b, mut go, mut gt, mut outputs) = (vec![], vec![], vec![], vec![], vec![]);
+
+        while let Some(key) = read_varint(reader)? {
+            match (key >> 3, key & 0x7) {
+                (FIELD_M, WIRE_VARINT) => nb_outputs = Some(read_u32(reader)?),
+                (FIELD_N, WIRE_VARINT) => nb_inputs = read_u32(reader)?,
+                (FIELD_A, wire_type) => read_repeated_u32(reader, wire_type, &mut a)?,
+                (FIELD_B, wire_type) => read_repeated_u32(reader, wire_type, &mut b)?,
+                (FIELD_GO, wire_type) => read_repeated_u32(reader, wire_type, &mut go)?,
+                (FIELD_GT, wire_type) => read_repeated_u32(reader, wire_type, &mut gt)?,
+                (FIELD_O, wire_type) => read_repeated_u32(reader, wire_type, &mut outputs)?,
+                (_, wire_type) => skip_field(reader, wire_type)?,
+            }
+        }
+
+        if [a.len(), b.len(), go.len()] != [gt.len(); 3] {
+            return Err(invalid_data("a/b/go/gt lengths mismatch"));
+        }
+        if nb_outputs.is_some_and(|nb_outputs| nb_outputs as usize != outputs.len()) {
+            return Err(invalid_data("m does NOT match the number of outputs"));
+        }
+        // NOT only a sanity check: the garbler allocates one label per wire
+        let nb_wires = u64::from(nb_inputs) + gt.len() as u64;
+        if go
+            .iter()
+            .chain(&outputs)
+            .any(|&wire| u64::from(wire) >= nb_wires)
+        {
+            return Err(invalid_data("wire out of range"));
+        }
+
+        let gates = (0..gt.len())
+            .map(|i| {
+                Ok(SkcdGate {
+                    a: a[i],
+                    b: b[i],
+                    o: go[i],
+                    gate_type: i32::try_from(gt[i])
+                        .ok()
+                        .and_then(SkcdGateType::from_i32)
+                        .ok_or_else(|| invalid_data(&format!("invalid gate type: {}", gt[i])))?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(Self {
+            nb_inputs,
+            gates,
+            outputs,
+        })
+    }
+
+    #[must_use]
+    pub fn nb_wires(&self) -> usize {
+        self.nb_inputs as usize + self.gates.len()
+    }
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("skcd: {msg}"))
+}
+
+/// return None on EOF before the first byte ie between two fields
+fn read_varint<R: Read>(reader: &mut R) -> Result<Option<u64>, Error> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        // NOTE: one byte at a time; `from_skcd_file` wraps the file in a BufReader
+        let mut byte = [0; 1];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                return if shift == 0 {
+                    Ok(None)
+                } else {
+                    Err(invalid_data("truncated varint"))
+                };
+            }
+            Err(err) => return Err(err),
+        }
+        let [byte] = byte;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+
+    Err(invalid_data("varint too long"))
+}
+
+fn read_required_varint<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    read_varint(reader)?.ok_or_else(|| invalid_data("unexpected EOF"))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, Error> {
+    u32::try_from(read_required_varint(reader)?).map_err(|_| invalid_data("u32 overflow"))
+}
+
+fn read_repeated_u32<R: Read>(
+    reader: &mut R,
+    wire_type: u64,
+    values: &mut Vec<u32>,
+) -> Result<(), Error> {
+    match wire_type {
+        WIRE_VARINT => values.push(read_u32(reader)?),
+        WIRE_LEN => {
+            let len = read_required_varint(reader)?;
+            let mut packed = reader.by_ref().take(len);
+            // NOTE: a truncated field is an "unexpected EOF" from `read_u32`
+            while packed.limit() > 0 {
+                values.push(read_u32(&mut packed)?);
+            }
+        }
+        _ => return Err(invalid_data(&format!("unexpected wire type: {wire_type}"))),
+    }
+    Ok(())
+}
+
+fn skip_field<R: Read>(reader: &mut R, wire_type: u64) -> Result<(), Error> {
+    let len = match wire_type {
+        WIRE_VARINT => {
+            read_required_varint(reader)?;
+            return Ok(());
+        }
+        WIRE_FIXED64 => 8,
+        WIRE_LEN => read_required_varint(reader)?,
+        WIRE_FIXED32 => 4,
+        _ => return Err(invalid_data(&format!("unsupported wire type: {wire_type}"))),
+    };
+
+    if std::io::copy(&mut reader.by_ref().take(len), &mut std::io::sink())? != len {
+        return Err(invalid_data("truncated field"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_skcd_buf() {
+        let skcd_buf = [
+            0x08, 2, // m
+            0x10, 3, // n
+            0x1a, 2, b'a', b'b', // unknown field 3: skipped
+            0x3a, 3, 6, 8, 6, // gt packed: Xor, And, Xor
+            0x38, 14, // gt NOT packed: Or
+        ];
+
+        let stats = SkcdStats::from_skcd_buf(&skcd_buf).unwrap();
+        assert_eq!(stats.nb_outputs, 2);
+        assert_eq!(stats.nb_inputs, 3);
+        assert_eq!(stats.nb_gates, 4);
+        assert_eq!(
+            stats.gates_by_type,
+            [
+                (SkcdGateType::Xor, 2),
+                (SkcdGateType::And, 1),
+                (SkcdGateType::Or, 1)
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_skcd_circuit_from_skcd_buf() {
+        let skcd_buf = [
+            0x08, 1, // m
+            0x10, 2, // n
+            0x22, 1, 0, // a packed
+            0x28, 1, // b NOT packed
+            0x32, 1, 2, // go packed
+            0x3a, 1, 8, // gt packed: And
+            0x42, 1, 2, // o packed
+        ];
+
+        let circuit = SkcdCircuit::from_skcd_buf(&skcd_buf).unwrap();
+        assert_eq!(circuit.nb_inputs, 2);
+        assert_eq!(
+            circuit.gates,
+            vec![SkcdGate {
+                a: 0,
+                b: 1,
+                o: 2,
+                gate_type: SkcdGateType::And
+            }]
+        );
+        assert_eq!(circuit.outputs, vec![2]);
+
+        // output wire 3 does NOT exist
+        let mut invalid = skcd_buf;
+        invalid[17] = 3;
+        assert!(SkcdCircuit::from_skcd_buf(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_from_skcd_buf_truncated() {
+        assert!(SkcdStats::from_skcd_buf(&[0x3a, 3, 6]).is_err());
+    }
+}
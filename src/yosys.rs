@@ -0,0 +1,334 @@
+// Copyright 2022 Nathan Prat
+
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+
+//     http://www.apache.org/licenses/LICENSE-2.0
+
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Runs the yosys binary(cf `--yosys-path`) on the untrusted Verilog of the
+// generic circuits. yosys exits the process on eg a parse error(`log_error`)
+// and has process-wide state, so that MUST NOT run inside the server.
+// NOTE: `Command` spawns with posix_spawn(or fork+exec with nothing in
+// between), contrary to a plain `fork` which is unsafe in this multithreaded process.
+//
+// The paths are passed in yosys scripts, which are split on whitespace: they
+// MUST NOT contain any; ie tmp files.
+
+use serde::de::{Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::process::Command;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiagnosticSeverity {
+    Error,
+    Warning,
+}
+
+/// A yosys error/warning
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogDiagnostic {
+    /// empty if NOT about a specific file
+    pub file: String,
+    /// 0 if unknown
+    pub line: u32,
+    pub severity: DiagnosticSeverity,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortDirection {
+    Input,
+    Output,
+    Inout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerilogPort {
+    pub name: String,
+    pub direction: PortDirection,
+    /// in bits
+    pub width: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerilogValidation {
+    pub diagnostics: Vec<VerilogDiagnostic>,
+    /// empty if the elaboration failed
+    pub top_module: String,
+    /// of `top_module`, in declaration order
+    pub ports: Vec<VerilogPort>,
+}
+
+/// Parse and elaborate(ie `read_verilog` + `hierarchy`) only: NO synthesis.
+/// The errors are returned as diagnostics, NOT as an Err.
+///
+/// # Errors
+/// if yosys could NOT run, or timed out
+pub async fn validate_verilog(
+    yosys_path: &Path,
+    verilog_path: &Path,
+    timeout: Duration,
+) -> Result<VerilogValidation, String> {
+    let json_path = verilog_path.with_extension("validation.json");
+    let script = format!(
+        "read_verilog -sv {}; hierarchy -check -auto-top; write_json {}",
+        verilog_path.display(),
+        json_path.display()
+    );
+    let (status, log) = run(yosys_path, &script, timeout).await?;
+
+    let mut validation = VerilogValidation {
+        diagnostics: parse_diagnostics(&log),
+        ..Default::default()
+    };
+    if status.success() {
+        let json = tokio::fs::read(&json_path)
+            .await
+            .map_err(|err| format!("yosys: no json: {err}"))?;
+        (validation.top_module, validation.ports) = parse_top_module(&json)?;
+    } else if !validation
+        .diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == DiagnosticSeverity::Error)
+    {
+        // eg crashed: there is no "ERROR:" line
+        validation.diagnostics.push(VerilogDiagnostic {
+            file: String::new(),
+            line: 0,
+            severity: DiagnosticSeverity::Error,
+            message: format!("yosys failed: {status}"),
+        });
+    }
+
+    Ok(validation)
+}
+
+/// return: the exit status and the whole log(stdout then stderr)
+async fn run(
+    yosys_path: &Path,
+    script: &str,
+    timeout: Duration,
+) -> Result<(ExitStatus, String), String> {
+    let child = Command::new(yosys_path)
+        // no banner nor footer: only the log of the passes
+        .args(["-Q", "-T", "-p", script])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // on timeout, and when the request is cancelled ie this future dropped
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| format!("could not run {}: {err}", yosys_path.display()))?;
+
+    let output = tokio::time::timeout(timeout, child.wait_with_output())
+        .await
+        .map_err(|_| format!("yosys: timeout after {timeout:?}"))?
+        .map_err(|err| format!("yosys: {err}"))?;
+    let mut log = String::from_utf8_lossy(&output.stdout).into_owned();
+    log.push_str(&String::from_utf8_lossy(&output.stderr));
+
+    Ok((output.status, log))
+}
+
+/// NOTE: an error can be both in stdout and stderr: reported once
+fn parse_diagnostics(log: &str) -> Vec<VerilogDiagnostic> {
+    let mut diagnostics: Vec<VerilogDiagnostic> = vec![];
+    for line in log.lines() {
+        let diagnostic = parse_diagnostic(line, "ERROR: ", DiagnosticSeverity::Error)
+            .or_else(|| parse_diagnostic(line, "Warning: ", DiagnosticSeverity::Warning));
+        if let Some(diagnostic) = diagnostic {
+            if !diagnostics.contains(&diagnostic) {
+                diagnostics.push(diagnostic);
+            }
+        }
+    }
+
+    diagnostics
+}
+
+/// eg "input.v:3: ERROR: syntax error, unexpected ';'" or "Warning: ..."
+/// return: None if `line` is NOT a diagnostic
+fn parse_diagnostic(
+    line: &str,
+    marker: &str,
+    severity: DiagnosticSeverity,
+) -> Option<VerilogDiagnostic> {
+    if let Some(message) = line.strip_prefix(marker) {
+        return Some(VerilogDiagnostic {
+            file: String::new(),
+            line: 0,
+            severity,
+            message: message.to_string(),
+        });
+    }
+
+    // "file:line: " + marker
+    let (location, message) = line.split_once(&format!(": {marker}"))?;
+    let (file, line) = match location.rsplit_once(':') {
+        Some((file, line)) => (file, line.parse().unwrap_or(0)),
+        None => (location, 0),
+    };
+    Some(VerilogDiagnostic {
+        file: file.to_string(),
+        line,
+        severity,
+        message: message.to_string(),
+    })
+}
+
+/// cf yosys' `write_json`
+#[derive(Deserialize)]
+struct JsonDesign {
+    modules: HashMap<String, JsonModule>,
+}
+
+#[derive(Deserialize)]
+struct JsonModule {
+    #[serde(default)]
+    attributes: HashMap<String, serde_json::Value>,
+    #[serde(default, deserialize_with = "ordered_ports")]
+    ports: Vec<(String, JsonPort)>,
+}
+
+#[derive(Deserialize)]
+struct JsonPort {
+    direction: PortDirection,
+    /// one per bit; either a net id or a constant
+    bits: Vec<serde_json::Value>,
+}
+
+/// The module with the "top" attribute(set by `hierarchy -auto-top`) and its ports
+fn parse_top_module(json: &[u8]) -> Result<(String, Vec<VerilogPort>), String> {
+    let design: JsonDesign = serde_json::from_slice(json).map_err(|err| err.to_string())?;
+    let (name, module) = design
+        .modules
+        .into_iter()
+        .find(|(_, module)| module.attributes.contains_key("top"))
+        .ok_or_else(|| "yosys: no top module".to_string())?;
+
+    let ports = module
+        .ports
+        .into_iter()
+        .map(|(name, port)| {
+            Ok(VerilogPort {
+                width: u32::try_from(port.bits.len()).map_err(|err| err.to_string())?,
+                name,
+                direction: port.direction,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+
+    Ok((name, ports))
+}
+
+/// `write_json` writes the ports in declaration order; a map would sort them
+fn ordered_ports<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<(String, JsonPort)>, D::Error> {
+    struct PortsVisitor;
+
+    impl<'de> Visitor<'de> for PortsVisitor {
+        type Value = Vec<(String, JsonPort)>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("a map of ports")
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            let mut ports = vec![];
+            while let Some(port) = map.next_entry()? {
+                ports.push(port);
+            }
+            Ok(ports)
+        }
+    }
+
+    deserializer.deserialize_map(PortsVisitor)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_diagnostics() {
+        let log = "1. Executing Verilog-2005 frontend: /tmp/x/input.v\n\
+                   /tmp/x/input.v:3: ERROR: syntax error, unexpected endmodule\n\
+                   Warning: Replacing memory \\mem with list of registers.\n\
+                   ERROR: syntax error, unexpected endmodule\n\
+                   /tmp/x/input.v:3: ERROR: syntax error, unexpected endmodule\n";
+
+        assert_eq!(
+            parse_diagnostics(log),
+            vec![
+                VerilogDiagnostic {
+                    file: "/tmp/x/input.v".to_string(),
+                    line: 3,
+                    severity: DiagnosticSeverity::Error,
+                    message: "syntax error, unexpected endmodule".to_string(),
+                },
+                VerilogDiagnostic {
+                    file: String::new(),
+                    line: 0,
+                    severity: DiagnosticSeverity::Warning,
+                    message: "Replacing memory \\mem with list of registers.".to_string(),
+                },
+                VerilogDiagnostic {
+                    file: String::new(),
+                    line: 0,
+                    severity: DiagnosticSeverity::Error,
+                    message: "syntax error, unexpected endmodule".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_top_module_keeps_ports_order() {
+        let json = br#"{
+            "creator": "Yosys",
+            "modules": {
+                "half_add": {
+                    "attributes": {},
+                    "ports": { "x": { "direction": "input", "bits": [ 2 ] } }
+                },
+                "full_add": {
+                    "attributes": { "top": "00000000000000000000000000000001" },
+                    "ports": {
+                        "b": { "direction": "input", "bits": [ 2, 3 ] },
+                        "a": { "direction": "input", "bits": [ 4, 5 ] },
+                        "sum": { "direction": "output", "bits": [ 6, "0" ] }
+                    }
+                }
+            }
+        }"#;
+
+        let (top_module, ports) = parse_top_module(json).unwrap();
+        assert_eq!(top_module, "full_add");
+        assert_eq!(
+            ports
+                .iter()
+                .map(|port| (port.name.as_str(), port.direction, port.width))
+                .collect::<Vec<_>>(),
+            vec![
+                ("b", PortDirection::Input, 2),
+                ("a", PortDirection::Input, 2),
+                ("sum", PortDirection::Output, 2),
+            ]
+        );
+    }
+}
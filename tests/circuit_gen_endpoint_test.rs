@@ -17,7 +17,11 @@
 
 // TODO? use integration_tests::pb::{test_client, test_server, Input, Output};
 // use ipfs_embed::{Config, DefaultParams, Ipfs};
+use api_circuits::auth;
+use api_circuits::cid;
+use api_circuits::circuits_ext_routes;
 use api_circuits::circuits_routes::{self, interstellarpbapicircuits::SkcdDisplayReply};
+use api_circuits::ipfs;
 use base64::{engine::general_purpose, Engine as _};
 use bytes::Buf;
 use bytes::BufMut;
@@ -26,8 +30,10 @@ use ipfs_api_backend_hyper::IpfsApi;
 use prost::Message;
 use std::io::Cursor;
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tests_utils::foreign_ipfs;
 use tokio::net::TcpListener;
+use tonic::codegen::InterceptedService;
 use tonic::{transport::Server, Request};
 use tonic_web::GrpcWebLayer;
 
@@ -228,17 +234,302 @@ async fn endpoint_generate_generic_protobuf() {
     );
 }
 
+#[tokio::test]
+async fn endpoint_generate_generic_deterministic_same_cid() {
+    let (foreign_node, ipfs_client) = run_ipfs_in_background().await;
+    let ipfs_server_multiaddr = format!("/ip4/127.0.0.1/tcp/{}", foreign_node.api_port);
+    let addr = run_service_in_background(&ipfs_server_multiaddr).await;
+
+    let verilog_data = std::fs::read_to_string("./tests/data/adder.v").unwrap();
+    let ipfs_result = ipfs_client.add(Cursor::new(verilog_data)).await.unwrap();
+
+    let mut client = interstellarpbapicircuits::skcd_ext_api_client::SkcdExtApiClient::connect(
+        format!("http://{}", addr),
+    )
+    .await
+    .unwrap();
+
+    let mut skcd_cids = vec![];
+    for _ in 0..2 {
+        let mut req = Request::new(
+            interstellarpbapicircuits::SkcdGenericFromIpfsWithOptionsRequest {
+                request: Some(interstellarpbapicircuits::SkcdGenericFromIpfsRequest {
+                    verilog_cid: ipfs_result.hash.clone(),
+                }),
+                options: Some(interstellarpbapicircuits::GenerateOptions {
+                    deterministic: true,
+                    ..Default::default()
+                }),
+            },
+        );
+        req.metadata_mut()
+            .insert("grpc-timeout", "30000m".parse().unwrap());
+
+        let resp = client
+            .generate_skcd_generic_from_ipfs_with_options(req)
+            .await
+            .unwrap();
+        assert_eq!(
+            resp.get_ref()
+                .port_map
+                .iter()
+                .map(|port| (port.name.as_str(), port.skcd_bits.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("a", vec![0]),
+                ("b", vec![1]),
+                ("cin", vec![2]),
+                ("sum", vec![0]),
+                ("cout", vec![1]),
+            ]
+        );
+        skcd_cids.push(resp.into_inner().skcd_cid);
+    }
+
+    assert_eq!(skcd_cids[0], skcd_cids[1]);
+}
+
+#[tokio::test]
+async fn endpoint_generate_display_deterministic_same_cid() {
+    let (foreign_node, _ipfs_client) = run_ipfs_in_background().await;
+    let ipfs_server_multiaddr = format!("/ip4/127.0.0.1/tcp/{}", foreign_node.api_port);
+    let addr = run_service_in_background(&ipfs_server_multiaddr).await;
+
+    let mut client = interstellarpbapicircuits::skcd_ext_api_client::SkcdExtApiClient::connect(
+        format!("http://{}", addr),
+    )
+    .await
+    .unwrap();
+
+    let mut skcd_cids = vec![];
+    for _ in 0..2 {
+        let mut req = Request::new(interstellarpbapicircuits::SkcdDisplayWithOptionsRequest {
+            request: Some(interstellarpbapicircuits::SkcdDisplayRequest {
+                width: 224,
+                height: 96,
+                digits_bboxes: vec![0.25_f32, 0.1_f32, 0.45_f32, 0.9_f32],
+            }),
+            options: Some(interstellarpbapicircuits::GenerateOptions {
+                deterministic: true,
+                ..Default::default()
+            }),
+            layout: None,
+            style: None,
+        });
+        req.metadata_mut()
+            .insert("grpc-timeout", "120000m".parse().unwrap());
+
+        let resp = client
+            .generate_skcd_display_with_options(req)
+            .await
+            .unwrap();
+        skcd_cids.push(resp.into_inner().skcd_cid);
+    }
+
+    assert_eq!(skcd_cids[0], skcd_cids[1]);
+}
+
+#[tokio::test]
+async fn endpoint_generate_display_batch_dedup() {
+    let (foreign_node, _ipfs_client) = run_ipfs_in_background().await;
+    let ipfs_server_multiaddr = format!("/ip4/127.0.0.1/tcp/{}", foreign_node.api_port);
+    let addr = run_service_in_background(&ipfs_server_multiaddr).await;
+
+    let mut client = interstellarpbapicircuits::skcd_ext_api_client::SkcdExtApiClient::connect(
+        format!("http://{}", addr),
+    )
+    .await
+    .unwrap();
+
+    let item = |width: u32| interstellarpbapicircuits::SkcdDisplayWithOptionsRequest {
+        request: Some(interstellarpbapicircuits::SkcdDisplayRequest {
+            width,
+            height: 96,
+            digits_bboxes: vec![0.25_f32, 0.1_f32, 0.45_f32, 0.9_f32],
+        }),
+        options: None,
+        layout: None,
+        style: None,
+    };
+    // the style does NOT change the circuit: still a duplicate, with its own palette
+    let styled = interstellarpbapicircuits::SkcdDisplayWithOptionsRequest {
+        style: Some(interstellarpbapicircuits::DisplayStyle {
+            foreground_rgba: Some(0xFF00_00FF),
+            digits_colors: vec![interstellarpbapicircuits::DigitColors {
+                foreground_rgba: None,
+                background_rgba: Some(0x0000_FFFF),
+            }],
+            ..Default::default()
+        }),
+        ..item(224)
+    };
+    let mut req = Request::new(tokio_stream::iter(vec![
+        item(224),
+        item(120),
+        item(224),
+        styled,
+    ]));
+    req.metadata_mut()
+        .insert("grpc-timeout", "240000m".parse().unwrap());
+
+    let mut results: Vec<_> = client
+        .generate_skcd_display_batch(req)
+        .await
+        .unwrap()
+        .into_inner()
+        .try_collect()
+        .await
+        .unwrap();
+    // streamed as they complete
+    results.sort_by_key(|result| result.index);
+
+    let reply = |index: usize| match &results[index].result {
+        Some(interstellarpbapicircuits::skcd_display_batch_result::Result::Reply(reply)) => {
+            reply.clone()
+        }
+        other => panic!("request {index} failed: {other:?}"),
+    };
+    assert_eq!(results.len(), 4);
+    assert_eq!(results[2].duplicate_of, Some(0));
+    assert_eq!(reply(0).skcd_cid, reply(2).skcd_cid);
+    assert_ne!(reply(0).skcd_cid, reply(1).skcd_cid);
+    assert_eq!(results[3].duplicate_of, Some(0));
+    assert_eq!(reply(0).skcd_cid, reply(3).skcd_cid);
+    assert_eq!(
+        reply(0).display_output.unwrap().palette,
+        vec![0x0000_00FF, 0xFFFF_FFFF]
+    );
+    let display_output = reply(3).display_output.unwrap();
+    assert_eq!(display_output.palette, vec![0x0000_00FF, 0xFF00_00FF]);
+    assert_eq!(
+        display_output.digits_palettes,
+        vec![interstellarpbapicircuits::Palette {
+            colors: vec![0x0000_FFFF, 0xFF00_00FF]
+        }]
+    );
+    assert_eq!(
+        display_output.digits_bboxes,
+        vec![0.25_f32, 0.1_f32, 0.45_f32, 0.9_f32]
+    );
+}
+
+/// The CIDs are computed locally and cross-checked cf `IpfsStorage::add`;
+/// that MUST also hold for files of several chunks ie a DAG with internal nodes.
+#[tokio::test]
+async fn ipfs_add_multi_chunk_cid_matches_local() {
+    let (foreign_node, ipfs_client) = run_ipfs_in_background().await;
+    let ipfs_server_multiaddr = format!("/ip4/127.0.0.1/tcp/{}", foreign_node.api_port);
+    let ipfs = ipfs::IpfsStorage::new(ipfs::IpfsConfig::new(vec![ipfs_server_multiaddr])).unwrap();
+
+    // 2 full chunks; then 2 and a partial one
+    for len in [2 * cid::CHUNK_SIZE, 2 * cid::CHUNK_SIZE + 1000] {
+        // NOT all zeros: the leaves MUST differ
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+        // kubo defaults ie CIDv0
+        let expected_cid = ipfs_client
+            .add(Cursor::new(data.clone()))
+            .await
+            .unwrap()
+            .hash;
+        assert_eq!(
+            cid::compute_cid(&data[..], cid::CidVersion::V0).unwrap(),
+            expected_cid
+        );
+        // `data_loss` if they do NOT match
+        ipfs.add(data.clone(), cid::CidVersion::V0).await.unwrap();
+        ipfs.add(data, cid::CidVersion::V1).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn endpoint_validate_verilog() {
+    let (foreign_node, ipfs_client) = run_ipfs_in_background().await;
+    let ipfs_server_multiaddr = format!("/ip4/127.0.0.1/tcp/{}", foreign_node.api_port);
+    let addr = run_service_in_background(&ipfs_server_multiaddr).await;
+
+    let mut client = interstellarpbapicircuits::skcd_ext_api_client::SkcdExtApiClient::connect(
+        format!("http://{}", addr),
+    )
+    .await
+    .unwrap();
+
+    let verilog_data = std::fs::read_to_string("./tests/data/adder.v").unwrap();
+    let valid_cid = ipfs_client
+        .add(Cursor::new(verilog_data))
+        .await
+        .unwrap()
+        .hash;
+    let resp = client
+        .validate_verilog(Request::new(
+            interstellarpbapicircuits::ValidateVerilogRequest {
+                verilog_cid: valid_cid,
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(resp.valid, "{:?}", resp.diagnostics);
+    assert_eq!(resp.top_module, "full_add");
+    assert_eq!(
+        resp.ports
+            .iter()
+            .map(|port| (port.name.as_str(), port.direction(), port.width))
+            .collect::<Vec<_>>(),
+        vec![
+            ("a", interstellarpbapicircuits::PortDirection::Input, 1),
+            ("b", interstellarpbapicircuits::PortDirection::Input, 1),
+            ("cin", interstellarpbapicircuits::PortDirection::Input, 1),
+            ("sum", interstellarpbapicircuits::PortDirection::Output, 1),
+            ("cout", interstellarpbapicircuits::PortDirection::Output, 1),
+        ]
+    );
+
+    let invalid_cid = ipfs_client
+        .add(Cursor::new("module broken(a);\n  input a\nendmodule\n"))
+        .await
+        .unwrap()
+        .hash;
+    let resp = client
+        .validate_verilog(Request::new(
+            interstellarpbapicircuits::ValidateVerilogRequest {
+                verilog_cid: invalid_cid.clone(),
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!resp.valid);
+    let error = &resp.diagnostics[0];
+    assert_eq!(
+        error.severity(),
+        interstellarpbapicircuits::DiagnosticSeverity::Error
+    );
+    assert_eq!((error.file.as_str(), error.line), (invalid_cid.as_str(), 3));
+}
+
 async fn run_service_in_background(ipfs_server_multiaddr: &str) -> SocketAddr {
     let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
     let addr = listener.local_addr().unwrap();
 
-    let circuits_api = circuits_routes::SkcdApiServerImpl {
-        ipfs_server_multiaddr: ipfs_server_multiaddr.to_string(),
-    };
-    let circuits_api =
-        circuits_routes::interstellarpbapicircuits::skcd_api_server::SkcdApiServer::new(
+    let ipfs = ipfs::IpfsStorage::new(ipfs::IpfsConfig::new(vec![
+        ipfs_server_multiaddr.to_string()
+    ]))
+    .unwrap();
+    let mut circuits_api = circuits_routes::SkcdApiServerImpl::new(Arc::new(ipfs));
+    circuits_api.generator_path = env!("CARGO_BIN_EXE_generate_skcd").into();
+    let circuits_api = Arc::new(circuits_api);
+    // like main.rs: auth disabled, but the handlers require a `Caller`
+    let circuits_ext_api = InterceptedService::new(
+        circuits_ext_routes::SkcdExtApiServer::from_arc(circuits_api.clone()),
+        auth::AuthInterceptor::default(),
+    );
+    let circuits_api = InterceptedService::new(
+        circuits_routes::interstellarpbapicircuits::skcd_api_server::SkcdApiServer::from_arc(
             circuits_api,
-        );
+        ),
+        auth::AuthInterceptor::default(),
+    );
 
     println!("GreeterServer listening on {}", addr);
 
@@ -247,6 +538,7 @@ async fn run_service_in_background(ipfs_server_multiaddr: &str) -> SocketAddr {
             .accept_http1(true)
             .layer(GrpcWebLayer::new())
             .add_service(circuits_api)
+            .add_service(circuits_ext_api)
             // .serve(addr) // NO!
             // thread 'cancelation_on_timeout' panicked at 'called `Result::unwrap()` on an `Err`
             // value: tonic::transport::Error(Transport, hyper::Error(Connect, ConnectError("tcp connect error",